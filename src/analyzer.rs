@@ -0,0 +1,617 @@
+use crate::{
+    environment::{Env, Variable},
+    error::SchemeError,
+    evaluator::validate_num_args,
+    types::{
+        function::Params, macros::original_name, record::RecordOperation, symbol::Symbol, Exp,
+        SpecialForm, Value,
+    },
+};
+use std::{cell::RefCell, rc::Rc};
+
+/*
+    Analysis - checks the syntax of an expanded expression once and converts
+    it into a tree of nodes, so running it does no parsing. The body of a
+    lambda is analyzed along with the lambda form, however many times the
+    closures made from it are called. Both backends run analyzed nodes: the
+    evaluator walks them and the compiler translates them to bytecode.
+
+    A malformed special form becomes a node raising the error, so the error
+    happens when the form is reached rather than when it is analyzed, and can
+    be caught like any other.
+
+    Variables are resolved to where they are bound as they are analyzed. Each
+    lambda, let and delay makes a frame of its parameters followed by the
+    names its body defines, which are found before the body is analyzed so
+    definitions can refer to each other. Names bound by no enclosing frame
+    are globals.
+*/
+
+#[derive(Clone, Debug)]
+pub(crate) enum Node {
+    Const(Exp),
+    Get(Variable),
+    Define(Variable, Rc<Node>),
+    Set(Variable, Rc<Node>),
+    If(Rc<If>),
+    Lambda(Rc<Template>),
+    CaseLambda(Rc<[Rc<Template>]>),
+    // A delay, or a delay-force if lazy, with the thunk computing its value
+    Delay(Rc<Template>, bool),
+    Begin(Rc<[Node]>),
+    And(Rc<[Node]>),
+    Or(Rc<[Node]>),
+    Let(Rc<Let>),
+    // The operator followed by the arguments
+    Call(Rc<[Node]>),
+    // Laid out like a call of apply to the function and the list of arguments
+    Apply(Rc<[Node]>),
+    Eval(Rc<Node>),
+    DefineRecordType(Rc<RecordDefinition>),
+    Error(SchemeError),
+}
+
+#[derive(Debug)]
+pub(crate) struct If {
+    pub(crate) test: Node,
+    pub(crate) consequent: Node,
+    pub(crate) alternative: Option<Node>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Let {
+    // The names bound by the let followed by those its body defines
+    pub(crate) frame: Rc<[Symbol]>,
+    pub(crate) values: Vec<Node>,
+    pub(crate) body: Rc<[Node]>,
+}
+
+/// A lambda form, shared by every closure made from it.
+#[derive(Debug)]
+pub(crate) struct Template {
+    pub(crate) params: Params,
+    // The parameters in the order they are bound, followed by the names the
+    // body defines
+    pub(crate) frame: Rc<[Symbol]>,
+    // The defaults of the optional then the keyword parameters, false for
+    // those without one
+    pub(crate) defaults: Vec<Node>,
+    // The list of body expressions as written, for displaying lambdas
+    pub(crate) source: Exp,
+    pub(crate) body: Rc<[Node]>,
+    // The name the lambda was defined or assigned to, for error messages
+    pub(crate) name: Option<Symbol>,
+}
+
+// Templates are only equal to themselves
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// A record type definition, with the variables it binds once analyzed. The
+/// type itself is made each time the definition runs, so each run defines a
+/// distinct type.
+#[derive(Debug)]
+pub(crate) struct RecordDefinition<T = Variable> {
+    pub(crate) name: T,
+    pub(crate) fields: Vec<String>,
+    pub(crate) procedures: Vec<(T, RecordOperation)>,
+}
+
+/// Analyzes an expanded expression to run in an environment.
+pub(crate) fn analyze(exp: &Exp, env: &Rc<RefCell<Env>>) -> Node {
+    let mut analyzer = Analyzer {
+        scope: env.borrow().scope(),
+    };
+    analyzer.analyze(exp)
+}
+
+struct Analyzer {
+    // The names bound by each enclosing frame, innermost last
+    scope: Vec<Vec<Symbol>>,
+}
+
+impl Analyzer {
+    fn analyze(&mut self, exp: &Exp) -> Node {
+        match exp {
+            Exp::Pair(_) if !exp.is_list() => Node::Error(SchemeError::new(format!(
+                "Cannot evaluate an improper list {}",
+                exp
+            ))),
+            Exp::Pair(_) => {
+                let (operator, args) = exp.split_first().unwrap();
+                match operator {
+                    Exp::Atom(Value::SpecialForm(form)) => self
+                        .analyze_special_form(form, args)
+                        .unwrap_or_else(Node::Error),
+                    operator => Node::Call(
+                        [vec![self.analyze(&operator)], self.analyze_each(&args)]
+                            .concat()
+                            .into(),
+                    ),
+                }
+            }
+            Exp::Nil => Node::Error(SchemeError::new(
+                "evaluate expects at least 1 args, found 0".to_string(),
+            )),
+            Exp::Atom(Value::Symbol(name)) => Node::Get(self.resolve(*name)),
+            Exp::Atom(Value::Quote(quoted)) => Node::Const((**quoted).clone()),
+            Exp::Atom(_) => Node::Const(exp.clone()),
+        }
+    }
+
+    fn analyze_each(&mut self, exps: &Exp) -> Vec<Node> {
+        exps.iter().map(|exp| self.analyze(&exp)).collect()
+    }
+
+    /// Finds the innermost frame binding a name. Later bindings of the same
+    /// name in a frame take its place.
+    fn resolve(&self, name: Symbol) -> Variable {
+        for (depth, frame) in self.scope.iter().rev().enumerate() {
+            if let Some(index) = frame.iter().rposition(|n| *n == name) {
+                return Variable::Local { depth, index, name };
+            }
+        }
+        Variable::Global(name)
+    }
+
+    /// The variable a definition binds, in the innermost frame or global at
+    /// the top level. Names not found before the body was analyzed, defined
+    /// somewhere other than the body or by eval, are added to the frame.
+    fn define(&mut self, name: Symbol) -> Variable {
+        let frame = match self.scope.last_mut() {
+            Some(frame) => frame,
+            None => return Variable::Global(name),
+        };
+        let index = match frame.iter().rposition(|n| *n == name) {
+            Some(index) => index,
+            None => {
+                frame.push(name);
+                frame.len() - 1
+            }
+        };
+        Variable::Local {
+            depth: 0,
+            index,
+            name,
+        }
+    }
+
+    /// Analyzes a body in a new frame binding the names, followed by those the
+    /// body defines, returning the frame along with the analyzed result.
+    fn in_frame<T>(
+        &mut self,
+        mut names: Vec<Symbol>,
+        body: &Exp,
+        analyze: impl FnOnce(&mut Self) -> T,
+    ) -> (Rc<[Symbol]>, T) {
+        for exp in body.iter() {
+            find_definitions(&exp, &mut names);
+        }
+        self.scope.push(names);
+        let result = analyze(self);
+        (self.scope.pop().unwrap().into(), result)
+    }
+
+    fn analyze_special_form(&mut self, form: SpecialForm, args: Exp) -> Result<Node, SchemeError> {
+        // Forms walking their arguments in turn keep sharing the list, the rest
+        // take it apart
+        match form {
+            SpecialForm::Lambda | SpecialForm::LambdaStar => {
+                let template = self.analyze_lambda(args, form == SpecialForm::LambdaStar)?;
+                return Ok(Node::Lambda(Rc::new(template)));
+            }
+            SpecialForm::Begin => return Ok(Node::Begin(self.analyze_each(&args).into())),
+            SpecialForm::And => return Ok(Node::And(self.analyze_each(&args).into())),
+            SpecialForm::Or => return Ok(Node::Or(self.analyze_each(&args).into())),
+            _ => {}
+        }
+        let list = args.unwrap_list()?;
+        match form {
+            SpecialForm::Define => {
+                validate_num_args("define", &list, 1, usize::MAX)?;
+                match &list[0] {
+                    Exp::Pair(_) => {
+                        // (define (name . params) body...)
+                        let (name, params) = list[0].split_first().unwrap();
+                        match name.unwrap_atom()? {
+                            Value::Symbol(name) => {
+                                let variable = self.define(name);
+                                let lambda_form_args = Exp::cons(params, args.cdr()?);
+                                let template = Template {
+                                    name: Some(name),
+                                    ..self.analyze_lambda(lambda_form_args, false)?
+                                };
+                                Ok(Node::Define(
+                                    variable,
+                                    Rc::new(Node::Lambda(Rc::new(template))),
+                                ))
+                            }
+                            _ => Err(SchemeError::new(format!(
+                                "Expected a symbol as the name, found {}",
+                                name
+                            ))),
+                        }
+                    }
+                    Exp::Atom(Value::Symbol(name)) => {
+                        validate_num_args("define value", &list, 2, 2)?;
+                        let variable = self.define(*name);
+                        let value = named(self.analyze(&list[1]), *name);
+                        Ok(Node::Define(variable, Rc::new(value)))
+                    }
+                    name => Err(SchemeError::new(format!(
+                        "Expected a symbol as the name, found {}",
+                        name
+                    ))),
+                }
+            }
+            SpecialForm::Set => {
+                validate_num_args("set!", &list, 2, 2)?;
+                match &list[0] {
+                    Exp::Atom(Value::Symbol(name)) => Ok(Node::Set(
+                        self.resolve(*name),
+                        Rc::new(named(self.analyze(&list[1]), *name)),
+                    )),
+                    name => Err(SchemeError::new(format!(
+                        "Expected a symbol as the name, found {}",
+                        name
+                    ))),
+                }
+            }
+            SpecialForm::Let => {
+                // The values are analyzed in the enclosing frame, the body in
+                // the let's own
+                let (names, values) = self.analyze_let_bindings(&list)?.into_iter().unzip();
+                let body = args.cdr()?;
+                let (frame, body) = self.in_frame(names, &body, |a| a.analyze_each(&body));
+                Ok(Node::Let(Rc::new(Let {
+                    frame,
+                    values,
+                    body: body.into(),
+                })))
+            }
+            SpecialForm::CaseLambda => Ok(Node::CaseLambda(
+                list.iter()
+                    .map(|clause| Ok(Rc::new(self.analyze_lambda(clause.clone(), false)?)))
+                    .collect::<Result<Vec<Rc<Template>>, SchemeError>>()?
+                    .into(),
+            )),
+            SpecialForm::DefineRecordType => Ok(Node::DefineRecordType(Rc::new(
+                self.analyze_record_type(&list)?,
+            ))),
+            SpecialForm::If => {
+                validate_num_args("if", &list, 2, 3)?;
+                Ok(Node::If(Rc::new(If {
+                    test: self.analyze(&list[0]),
+                    consequent: self.analyze(&list[1]),
+                    alternative: list.get(2).map(|exp| self.analyze(exp)),
+                })))
+            }
+            SpecialForm::Delay | SpecialForm::DelayForce => {
+                validate_num_args(&form.to_string(), &list, 1, 1)?;
+                let (frame, body) = self.in_frame(Vec::new(), &args, |a| a.analyze_each(&args));
+                let thunk = Template {
+                    params: Params::default(),
+                    frame,
+                    defaults: Vec::new(),
+                    body: body.into(),
+                    source: args,
+                    name: None,
+                };
+                Ok(Node::Delay(Rc::new(thunk), form == SpecialForm::DelayForce))
+            }
+            SpecialForm::Eval => {
+                validate_num_args("eval", &list, 1, 1)?;
+                Ok(Node::Eval(Rc::new(self.analyze(&list[0]))))
+            }
+            SpecialForm::Apply => {
+                validate_num_args("apply", &list, 2, 2)?;
+                let apply = Node::Const(Exp::Atom(Value::SpecialForm(form)));
+                Ok(Node::Apply(
+                    [vec![apply], self.analyze_each(&args)].concat().into(),
+                ))
+            }
+            SpecialForm::Lambda
+            | SpecialForm::LambdaStar
+            | SpecialForm::Begin
+            | SpecialForm::And
+            | SpecialForm::Or => unreachable!("handled above"),
+            SpecialForm::DefineSyntax
+            | SpecialForm::DefineMacro
+            | SpecialForm::LetSyntax
+            | SpecialForm::LetrecSyntax
+            | SpecialForm::SyntaxRules
+            | SpecialForm::DefineStar
+            | SpecialForm::Cond
+            | SpecialForm::Case
+            | SpecialForm::When
+            | SpecialForm::Unless
+            | SpecialForm::LetStar
+            | SpecialForm::Letrec
+            | SpecialForm::LetrecStar
+            | SpecialForm::Guard
+            | SpecialForm::LetValues
+            | SpecialForm::LetStarValues
+            | SpecialForm::DefineValues
+            | SpecialForm::Receive => Err(SchemeError::new(format!(
+                "{} is only valid during macro expansion",
+                form
+            ))),
+        }
+    }
+
+    /// The names and analyzed values of the bindings of a let.
+    fn analyze_let_bindings(&mut self, args: &[Exp]) -> Result<Vec<(Symbol, Node)>, SchemeError> {
+        validate_num_args("let", args, 2, usize::MAX)?;
+        if !args[0].is_list() {
+            return Err(SchemeError::new(
+                "Let expects a list of definitions".to_string(),
+            ));
+        }
+        let mut bindings = Vec::new();
+        for pair in args[0].iter() {
+            match pair.unwrap_list() {
+                Ok(pair_vec) if pair_vec.len() == 2 => match &pair_vec[0] {
+                    Exp::Atom(Value::Symbol(name)) => {
+                        bindings.push((*name, named(self.analyze(&pair_vec[1]), *name)))
+                    }
+                    name => {
+                        return Err(SchemeError::new(format!(
+                            "Expected a symbol as the name, found {}",
+                            name
+                        )))
+                    }
+                },
+                _ => {
+                    return Err(SchemeError::new(format!(
+                        "Let expects (name value) definitions, found {}",
+                        pair
+                    )))
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Analyzes the parameters and body of a lambda or lambda* form.
+    fn analyze_lambda(&mut self, args: Exp, extended: bool) -> Result<Template, SchemeError> {
+        let (params, body) = match args.split_first() {
+            Some((params, body @ Exp::Pair(_))) => (params, body),
+            _ => {
+                let name = match extended {
+                    true => "lambda*",
+                    false => "lambda",
+                };
+                validate_num_args(name, &args.unwrap_list()?, 2, usize::MAX)?;
+                unreachable!("a lambda form with a body is a list of at least 2")
+            }
+        };
+        let params = eval_params(&params, extended)?;
+        let names = params
+            .required
+            .iter()
+            .chain(
+                params
+                    .optional
+                    .iter()
+                    .chain(&params.keys)
+                    .map(|(name, _)| name),
+            )
+            .chain(&params.rest)
+            .cloned()
+            .collect();
+        // Defaults are evaluated in the lambda's frame, after the parameters
+        // before them are bound
+        let (frame, (defaults, nodes)) = self.in_frame(names, &body, |a| {
+            let defaults = params
+                .optional
+                .iter()
+                .chain(&params.keys)
+                .map(|(_, default)| match default {
+                    Some(default) => a.analyze(default),
+                    // Parameters without a default are bound to false
+                    None => Node::Const(Exp::Atom(Value::Boolean(false))),
+                })
+                .collect();
+            (defaults, a.analyze_each(&body))
+        });
+        Ok(Template {
+            params,
+            frame,
+            defaults,
+            body: nodes.into(),
+            source: body,
+            name: None,
+        })
+    }
+
+    fn analyze_record_type(&mut self, args: &[Exp]) -> Result<RecordDefinition, SchemeError> {
+        let definition = parse_record_type(args)?;
+        Ok(RecordDefinition {
+            name: self.define(definition.name),
+            fields: definition.fields,
+            procedures: definition
+                .procedures
+                .into_iter()
+                .map(|(name, operation)| (self.define(name), operation))
+                .collect(),
+        })
+    }
+}
+
+/// Names a lambda being bound or assigned to a variable, unless it has a
+/// name already, with the name as written before any macro renamed it.
+fn named(node: Node, name: Symbol) -> Node {
+    match node {
+        Node::Lambda(mut template) => {
+            if let Some(template) = Rc::get_mut(&mut template) {
+                template.name.get_or_insert_with(|| original_name(name));
+            }
+            Node::Lambda(template)
+        }
+        node => node,
+    }
+}
+
+/// Adds the names a body expression defines to a frame, looking inside begin
+/// forms, so they are bound before the body is analyzed.
+fn find_definitions(exp: &Exp, names: &mut Vec<Symbol>) {
+    let mut define = |name: Symbol| {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    };
+    let (form, args) = match exp.split_first() {
+        Some((Exp::Atom(Value::SpecialForm(form)), args)) if exp.is_list() => (form, args),
+        _ => return,
+    };
+    match (form, args.car()) {
+        (SpecialForm::Define, Ok(Exp::Atom(Value::Symbol(name)))) => define(name),
+        (SpecialForm::Define, Ok(signature @ Exp::Pair(_))) => {
+            if let Ok(Exp::Atom(Value::Symbol(name))) = signature.car() {
+                define(name)
+            }
+        }
+        (SpecialForm::DefineRecordType, _) => {
+            if let Ok(definition) = parse_record_type(&args.unwrap_list().unwrap()) {
+                define(definition.name);
+                for (name, _) in definition.procedures {
+                    define(name);
+                }
+            }
+        }
+        (SpecialForm::Begin, _) => args.iter().for_each(|exp| find_definitions(&exp, names)),
+        _ => {}
+    }
+}
+
+/// Parses a record type definition into the name of the type, its fields
+/// and its constructor, predicate, accessors and modifiers:
+/// (define-record-type <point> (make-point x y) point? (x point-x set-point-x!) ...)
+/// The constructor can also be a bare name taking every field, and the
+/// constructor or predicate can be #f to leave them out.
+fn parse_record_type(args: &[Exp]) -> Result<RecordDefinition<Symbol>, SchemeError> {
+    validate_num_args("define-record-type", args, 3, usize::MAX)?;
+    let field_specs = args[3..]
+        .iter()
+        .map(|spec| match spec {
+            Exp::Pair(_) => {
+                let spec = spec.unwrap_list()?;
+                validate_num_args("field spec", &spec, 1, 3)?;
+                spec.iter()
+                    .map(eval_param)
+                    .collect::<Result<Vec<Symbol>, SchemeError>>()
+            }
+            _ => Ok(vec![eval_param(spec)?]),
+        })
+        .collect::<Result<Vec<Vec<Symbol>>, SchemeError>>()?;
+    let name = eval_param(&args[0])?;
+    let fields: Vec<Symbol> = field_specs.iter().map(|spec| spec[0]).collect();
+    let field_index = |field: Symbol| {
+        fields
+            .iter()
+            .position(|name| *name == field)
+            .ok_or_else(|| SchemeError::new(format!("{} is not a field of {}", field, name)))
+    };
+
+    let mut procedures = Vec::new();
+    let constructor = match &args[1] {
+        Exp::Pair(_) => {
+            let signature = args[1].unwrap_list()?;
+            Some((
+                eval_param(&signature[0])?,
+                signature[1..]
+                    .iter()
+                    .map(|field| field_index(eval_param(field)?))
+                    .collect::<Result<Vec<usize>, SchemeError>>()?,
+            ))
+        }
+        Exp::Atom(Value::Boolean(false)) => None,
+        name => Some((eval_param(name)?, (0..fields.len()).collect())),
+    };
+    if let Some((name, fields)) = constructor {
+        procedures.push((name, RecordOperation::Constructor(fields)));
+    }
+    if !matches!(args[2], Exp::Atom(Value::Boolean(false))) {
+        procedures.push((eval_param(&args[2])?, RecordOperation::Predicate));
+    }
+    for (field, spec) in field_specs.into_iter().enumerate() {
+        let mut names = spec.into_iter().skip(1);
+        if let Some(accessor) = names.next() {
+            procedures.push((accessor, RecordOperation::Accessor(field)));
+        }
+        if let Some(modifier) = names.next() {
+            procedures.push((modifier, RecordOperation::Modifier(field)));
+        }
+    }
+    Ok(RecordDefinition {
+        name,
+        fields: fields.iter().map(|field| field.to_string()).collect(),
+        procedures,
+    })
+}
+
+/// Parses a parameter list: (a b), (a b . rest) or rest. For lambda* the
+/// list can also have #:optional and #:key sections, whose parameters are
+/// either a name or (name default), and a #:rest parameter.
+fn eval_params(params: &Exp, extended: bool) -> Result<Params, SchemeError> {
+    let mut list = params.iter();
+    let params = list.by_ref().collect::<Vec<Exp>>();
+    let rest = match list.rest {
+        Exp::Nil => None,
+        rest => Some(eval_param(&rest)?),
+    };
+    let mut result = Params {
+        rest,
+        ..Params::default()
+    };
+    let mut section = "";
+    let mut list = params.iter();
+    while let Some(param) = list.next() {
+        match param {
+            Exp::Atom(Value::Keyword(keyword))
+                if extended && ["optional", "key", "rest"].contains(&keyword.as_str()) =>
+            {
+                section = keyword;
+                if section == "rest" {
+                    match (list.next(), list.next(), &result.rest) {
+                        (Some(rest), None, None) => result.rest = Some(eval_param(rest)?),
+                        _ => {
+                            return Err(SchemeError::new(
+                                "#:rest expects a single parameter".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => match section {
+                "optional" => result.optional.push(eval_default_param(param)?),
+                "key" => result.keys.push(eval_default_param(param)?),
+                _ => result.required.push(eval_param(param)?),
+            },
+        }
+    }
+    Ok(result)
+}
+
+fn eval_default_param(param: &Exp) -> Result<(Symbol, Option<Exp>), SchemeError> {
+    match param {
+        Exp::Pair(_) => match &param.unwrap_list()?[..] {
+            [name, default] => Ok((eval_param(name)?, Some(default.clone()))),
+            _ => Ok((eval_param(param)?, None)),
+        },
+        _ => Ok((eval_param(param)?, None)),
+    }
+}
+
+fn eval_param(param: &Exp) -> Result<Symbol, SchemeError> {
+    match param {
+        Exp::Atom(Value::Symbol(name)) => Ok(*name),
+        _ => Err(SchemeError::new(format!(
+            "Parameter list expects symbols, found {}",
+            param
+        ))),
+    }
+}
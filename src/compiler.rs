@@ -0,0 +1,277 @@
+use crate::{
+    analyzer::{If, Let, Node, RecordDefinition, Template},
+    environment::Variable,
+    error::SchemeError,
+    types::{Exp, Value},
+};
+use std::{
+    fmt::{self, Debug},
+    rc::Rc,
+};
+
+/*
+    Compilation - translates analyzed nodes into bytecode for the virtual
+    machine. Each lambda body, let body and delayed expression is compiled to
+    its own Code, referenced from the code around it. Code in tail position
+    ends by returning or by a tail call, so the virtual machine runs tail
+    calls in constant space like the evaluator.
+*/
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Op {
+    // Pushes a constant
+    Const(usize),
+    // Pushes the value of a variable
+    Get(usize),
+    // Pops a value and binds or assigns it to a variable, pushing nil
+    Define(usize),
+    Set(usize),
+    Pop,
+    Jump(usize),
+    // Pops a value and jumps if it is false
+    JumpIfFalse(usize),
+    // Jumps keeping the value if it is false (for and) or true (for or),
+    // otherwise pops it
+    AndJump(usize),
+    OrJump(usize),
+    // Pushes a lambda, case-lambda or promise closing over the environment
+    Lambda(usize),
+    CaseLambda(usize, usize),
+    Delay(usize, bool),
+    DefineRecordType(usize),
+    // Pops the values of a let and runs its body in a new frame
+    Let(usize),
+    TailLet(usize),
+    // Pops the arguments and the operator of a call
+    Call(usize),
+    TailCall(usize),
+    // Pops a list of arguments and the operator to apply to them
+    Apply,
+    TailApply,
+    // Pops an expression, then expands, compiles and runs it
+    Eval,
+    Return,
+    Error(usize),
+}
+
+#[derive(Default)]
+pub(crate) struct Code {
+    pub(crate) ops: Vec<Op>,
+    pub(crate) constants: Vec<Exp>,
+    pub(crate) variables: Vec<Variable>,
+    // The lambdas, case-lambda clauses and delays, with their compiled bodies
+    pub(crate) templates: Vec<(Rc<Template>, Rc<Code>)>,
+    pub(crate) lets: Vec<(Rc<Let>, Rc<Code>)>,
+    pub(crate) records: Vec<Rc<RecordDefinition>>,
+    pub(crate) errors: Vec<SchemeError>,
+}
+
+impl Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Code of {} ops", self.ops.len())
+    }
+}
+
+// Code is only equal to itself
+impl PartialEq for Code {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Compiles an analyzed top level expression.
+pub(crate) fn compile(node: &Node) -> Rc<Code> {
+    let mut code = Code::default();
+    code.compile(node, true);
+    Rc::new(code)
+}
+
+/// Compiles a body, the expressions of which are evaluated in turn.
+fn compile_body(body: &[Node]) -> Rc<Code> {
+    let mut code = Code::default();
+    code.body(body, true);
+    Rc::new(code)
+}
+
+impl Code {
+    fn emit(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.ops.len() - 1
+    }
+
+    /// Points the jump at `at` to the next op.
+    fn patch(&mut self, at: usize) {
+        let target = self.ops.len();
+        self.ops[at] = match self.ops[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::AndJump(_) => Op::AndJump(target),
+            Op::OrJump(_) => Op::OrJump(target),
+            op => unreachable!("{:?} is not a jump", op),
+        };
+    }
+
+    /// Returns the value just pushed if in tail position.
+    fn tail(&mut self, tail: bool) {
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn constant(&mut self, exp: Exp, tail: bool) {
+        self.constants.push(exp);
+        self.emit(Op::Const(self.constants.len() - 1));
+        self.tail(tail);
+    }
+
+    fn variable(&mut self, variable: &Variable) -> usize {
+        self.variables.push(*variable);
+        self.variables.len() - 1
+    }
+
+    fn template(&mut self, template: &Rc<Template>) -> usize {
+        let code = compile_body(&template.body);
+        self.templates.push((template.clone(), code));
+        self.templates.len() - 1
+    }
+
+    fn compile(&mut self, node: &Node, tail: bool) {
+        match node {
+            Node::Const(value) => self.constant(value.clone(), tail),
+            Node::Get(variable) => {
+                let index = self.variable(variable);
+                self.emit(Op::Get(index));
+                self.tail(tail);
+            }
+            Node::Define(variable, value) | Node::Set(variable, value) => {
+                self.compile(value, false);
+                let index = self.variable(variable);
+                self.emit(match node {
+                    Node::Define(..) => Op::Define(index),
+                    _ => Op::Set(index),
+                });
+                self.tail(tail);
+            }
+            Node::If(node) => self.compile_if(node, tail),
+            Node::Lambda(template) => {
+                let index = self.template(template);
+                self.emit(Op::Lambda(index));
+                self.tail(tail);
+            }
+            Node::CaseLambda(templates) => {
+                let start = self.templates.len();
+                for template in templates.iter() {
+                    self.template(template);
+                }
+                self.emit(Op::CaseLambda(start, templates.len()));
+                self.tail(tail);
+            }
+            Node::Delay(template, lazy) => {
+                let index = self.template(template);
+                self.emit(Op::Delay(index, *lazy));
+                self.tail(tail);
+            }
+            Node::Begin(body) => self.body(body, tail),
+            Node::And(args) => self.and_or(args, true, tail),
+            Node::Or(args) => self.and_or(args, false, tail),
+            Node::Let(node) => {
+                for value in &node.values {
+                    self.compile(value, false);
+                }
+                self.lets.push((node.clone(), compile_body(&node.body)));
+                let index = self.lets.len() - 1;
+                self.emit(match tail {
+                    true => Op::TailLet(index),
+                    false => Op::Let(index),
+                });
+            }
+            Node::Call(call) => {
+                for node in call.iter() {
+                    self.compile(node, false);
+                }
+                self.emit(match tail {
+                    true => Op::TailCall(call.len() - 1),
+                    false => Op::Call(call.len() - 1),
+                });
+            }
+            Node::Apply(call) => {
+                // Leaving out apply itself
+                for node in &call[1..] {
+                    self.compile(node, false);
+                }
+                self.emit(match tail {
+                    true => Op::TailApply,
+                    false => Op::Apply,
+                });
+            }
+            Node::Eval(exp) => {
+                self.compile(exp, false);
+                self.emit(Op::Eval);
+                self.tail(tail);
+            }
+            Node::DefineRecordType(definition) => {
+                self.records.push(definition.clone());
+                self.emit(Op::DefineRecordType(self.records.len() - 1));
+                self.tail(tail);
+            }
+            Node::Error(error) => {
+                self.errors.push(error.clone());
+                self.emit(Op::Error(self.errors.len() - 1));
+            }
+        }
+    }
+
+    fn compile_if(&mut self, node: &If, tail: bool) {
+        self.compile(&node.test, false);
+        let alternative = self.emit(Op::JumpIfFalse(0));
+        self.compile(&node.consequent, tail);
+        let end = match tail {
+            true => None,
+            false => Some(self.emit(Op::Jump(0))),
+        };
+        self.patch(alternative);
+        match &node.alternative {
+            Some(exp) => self.compile(exp, tail),
+            None => self.constant(Exp::new_list(), tail),
+        }
+        if let Some(end) = end {
+            self.patch(end);
+        }
+    }
+
+    /// Compiles the expressions of a body in turn, discarding the values of
+    /// all but the last.
+    fn body(&mut self, body: &[Node], tail: bool) {
+        match body.split_last() {
+            None => self.constant(Exp::new_list(), tail),
+            Some((last, exps)) => {
+                for exp in exps {
+                    self.compile(exp, false);
+                    self.emit(Op::Pop);
+                }
+                self.compile(last, tail);
+            }
+        }
+    }
+
+    /// Compiles and/or, jumping to the end with the value that decides it.
+    fn and_or(&mut self, args: &[Node], and: bool, tail: bool) {
+        let (last, exps) = match args.split_last() {
+            Some(split) => split,
+            None => return self.constant(Exp::Atom(Value::Boolean(and)), tail),
+        };
+        let mut jumps = Vec::new();
+        for exp in exps {
+            self.compile(exp, false);
+            jumps.push(self.emit(match and {
+                true => Op::AndJump(0),
+                false => Op::OrJump(0),
+            }));
+        }
+        self.compile(last, tail);
+        for jump in jumps {
+            self.patch(jump);
+        }
+        self.tail(tail);
+    }
+}
@@ -1,49 +1,281 @@
 use crate::{
     error::SchemeError,
-    types::{default_env::builtins_map, Exp},
+    gc::{self, Freed, Tracer},
+    types::{default_env::builtins_map, symbol::Symbol, Exp},
 };
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-#[derive(Clone, PartialEq)]
+/*
+    Environment - a chain of local frames ending at the global environment.
+    Local frames hold their values in slots, found by the lexical address the
+    analyzer resolved each variable to, along with the names of the slots for
+    code analyzed at runtime. Globals are kept in a table shared by every
+    frame, so they are found without walking the chain.
+*/
+
+/// The table of global variables shared by the frames of an environment.
+pub(crate) type Globals = RefCell<HashMap<Symbol, Exp>>;
+
 pub(crate) struct Env {
-    pub(crate) table: HashMap<String, Exp>,
+    globals: Rc<Globals>,
+    names: Rc<[Symbol]>,
+    // Slots of internal definitions that haven't run yet are empty
+    slots: Vec<Option<Exp>>,
     parent: Option<Rc<RefCell<Env>>>,
+    // Where the heap tracks it
+    heap_slot: usize,
+}
+
+/// Where a variable is bound, resolved when the code using it is analyzed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Variable {
+    // The slot at index of the frame depth frames up from the current one
+    Local {
+        depth: usize,
+        index: usize,
+        name: Symbol,
+    },
+    Global(Symbol),
+}
+
+impl Variable {
+    pub(crate) fn name(&self) -> Symbol {
+        match self {
+            Variable::Local { name, .. } | Variable::Global(name) => *name,
+        }
+    }
 }
 
 impl Env {
-    pub(crate) fn new() -> Self {
-        Self {
-            table: builtins_map(),
-            parent: None,
+    /// A new global environment holding the builtins.
+    pub(crate) fn new() -> Rc<RefCell<Self>> {
+        let globals = Rc::new(RefCell::new(
+            builtins_map()
+                .into_iter()
+                .map(|(name, value)| (Symbol::new(&name), value))
+                .collect(),
+        ));
+        gc::track_globals(&globals);
+        Rc::new_cyclic(|env| {
+            RefCell::new(Self {
+                globals,
+                names: Rc::from([]),
+                slots: Vec::new(),
+                parent: None,
+                heap_slot: gc::track_env(env),
+            })
+        })
+    }
+
+    /// The names bound by each local frame, outermost first, which code
+    /// analyzed to run in this environment resolves variables against.
+    pub(crate) fn scope(&self) -> Vec<Vec<Symbol>> {
+        let mut scope = match &self.parent {
+            Some(parent) => parent.borrow().scope(),
+            None => return Vec::new(),
+        };
+        scope.push(self.names.to_vec());
+        scope
+    }
+
+    /// Looks up a name through the local frames, then the globals.
+    pub(crate) fn get(&self, name: Symbol) -> Result<Exp, SchemeError> {
+        if let Some(index) = self.names.iter().rposition(|n| *n == name) {
+            if let Some(val) = &self.slots[index] {
+                return Ok(val.clone());
+            }
+        }
+        match &self.parent {
+            Some(parent) => parent.borrow().get(name),
+            None => self.lookup_global(name),
         }
     }
 
-    pub(crate) fn get(&self, name: &str) -> Result<Exp, SchemeError> {
-        if let Some(val) = self.table.get(name) {
-            return Ok(val.clone());
+    /// Binds a name in this frame, or in the globals if this is the global
+    /// environment.
+    pub(crate) fn set(&mut self, name: Symbol, val: &Exp) {
+        if self.parent.is_none() {
+            self.globals.borrow_mut().insert(name, val.clone());
+            return;
         }
-        if let Some(parent) = &self.parent {
-            if let Ok(val) = parent.borrow().get(name) {
-                return Ok(val.clone());
+        let index = match self.names.iter().rposition(|n| *n == name) {
+            Some(index) => index,
+            None => self.add_slot(name),
+        };
+        self.slots[index] = Some(val.clone());
+    }
+
+    pub(crate) fn lookup(&self, variable: &Variable) -> Result<Exp, SchemeError> {
+        match variable {
+            Variable::Local {
+                depth: 0,
+                index,
+                name,
+            } => self.slot(*index, *name),
+            Variable::Local { depth, index, name } => {
+                self.frame(*depth).borrow().slot(*index, *name)
+            }
+            // A name eval defined in a local frame was taken to be global by
+            // code analyzed before the definition ran
+            Variable::Global(name) => self.lookup_global(*name).or_else(|_| self.get(*name)),
+        }
+    }
+
+    /// Binds a variable resolved by a definition, which is always in this
+    /// frame or global.
+    pub(crate) fn define(&mut self, variable: &Variable, val: &Exp) {
+        match variable {
+            Variable::Local { index, name, .. } => {
+                // Definitions run by eval can add to a frame
+                while self.slots.len() <= *index {
+                    self.add_slot(*name);
+                }
+                self.slots[*index] = Some(val.clone());
             }
+            Variable::Global(name) => self.set(*name, val),
         }
-        Err(SchemeError::new(format!("Name {} not found", name)))
     }
 
-    pub(crate) fn set(&mut self, name: &str, val: &Exp) {
-        self.table.insert(name.to_string(), val.clone());
+    /// Changes the value of an existing binding, unlike define which always
+    /// binds.
+    pub(crate) fn assign(&mut self, variable: &Variable, val: &Exp) -> Result<(), SchemeError> {
+        let assigned = match variable {
+            Variable::Local {
+                depth: 0, index, ..
+            } => self.assign_slot(*index, val),
+            Variable::Local { depth, index, .. } => {
+                self.frame(*depth).borrow_mut().assign_slot(*index, val)
+            }
+            Variable::Global(name) => match self.globals.borrow_mut().get_mut(name) {
+                Some(slot) => {
+                    *slot = val.clone();
+                    true
+                }
+                None => false,
+            },
+        };
+        match assigned {
+            true => Ok(()),
+            false => Err(SchemeError::new(format!(
+                "Cannot set! unbound name {}",
+                variable.name()
+            ))),
+        }
+    }
+
+    /// Fills the slot of a parameter bound once the frame is made.
+    pub(crate) fn set_slot(&mut self, index: usize, val: &Exp) {
+        self.slots[index] = Some(val.clone());
+    }
+
+    fn slot(&self, index: usize, name: Symbol) -> Result<Exp, SchemeError> {
+        match &self.slots[index] {
+            Some(val) => Ok(val.clone()),
+            None => Err(SchemeError::new(format!("Name {} not found", name))),
+        }
+    }
+
+    fn assign_slot(&mut self, index: usize, val: &Exp) -> bool {
+        match &mut self.slots[index] {
+            Some(slot) => {
+                *slot = val.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lookup_global(&self, name: Symbol) -> Result<Exp, SchemeError> {
+        match self.globals.borrow().get(&name) {
+            Some(val) => Ok(val.clone()),
+            None => Err(SchemeError::new(format!("Name {} not found", name))),
+        }
+    }
+
+    /// The frame depth frames up from this one.
+    fn frame(&self, depth: usize) -> Rc<RefCell<Env>> {
+        let mut frame = self.parent.clone().unwrap();
+        for _ in 1..depth {
+            let parent = frame.borrow().parent.clone().unwrap();
+            frame = parent;
+        }
+        frame
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.object(&self.globals);
+        if let Some(parent) = &self.parent {
+            tracer.object(parent);
+        }
+        self.slots.iter().flatten().for_each(|val| tracer.exp(val));
+    }
+
+    /// Takes the values and parent out of a frame being collected, for the
+    /// caller to drop.
+    pub(crate) fn clear(&mut self) -> (Vec<Option<Exp>>, Option<Rc<RefCell<Env>>>) {
+        (std::mem::take(&mut self.slots), self.parent.take())
+    }
+
+    fn add_slot(&mut self, name: Symbol) -> usize {
+        let mut names = self.names.to_vec();
+        names.push(name);
+        self.names = names.into();
+        self.slots.push(None);
+        self.slots.len() - 1
+    }
+}
+
+// Frames are equal if they hold the same bindings, wherever they are tracked
+impl PartialEq for Env {
+    fn eq(&self, other: &Self) -> bool {
+        self.globals == other.globals
+            && self.names == other.names
+            && self.slots == other.slots
+            && self.parent == other.parent
+    }
+}
+
+// Sets aside the values and parent of a frame being freed, so freeing a long
+// chain of closures, each holding the frame of the last, doesn't recurse
+impl Drop for Env {
+    fn drop(&mut self) {
+        gc::untrack_env(self.heap_slot);
+        let (slots, parent) = self.clear();
+        gc::free(
+            slots
+                .into_iter()
+                .flatten()
+                .map(Freed::Exp)
+                .chain(parent.map(Freed::Env)),
+        );
     }
 }
 
 impl std::fmt::Debug for Env {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}, parent: {:?}", self.table.keys(), self.parent)
+        match &self.parent {
+            Some(parent) => write!(f, "{:?}, parent: {:?}", self.names, parent),
+            None => write!(f, "{:?}", self.globals.borrow().keys()),
+        }
     }
 }
 
-pub(crate) fn create_closure<'a>(parent: Rc<RefCell<Env>>) -> Rc<RefCell<Env>> {
-    Rc::new(RefCell::new(Env {
-        table: HashMap::new(),
-        parent: Some(parent),
-    }))
+/// Makes a local frame binding the names to the values in order. Any names
+/// left over are internal definitions that haven't run yet.
+pub(crate) fn create_closure(
+    parent: Rc<RefCell<Env>>,
+    names: Rc<[Symbol]>,
+    mut slots: Vec<Option<Exp>>,
+) -> Rc<RefCell<Env>> {
+    slots.resize(names.len(), None);
+    let globals = parent.borrow().globals.clone();
+    Rc::new_cyclic(|env| {
+        RefCell::new(Env {
+            globals,
+            names,
+            slots,
+            parent: Some(parent),
+            heap_slot: gc::track_env(env),
+        })
+    })
 }
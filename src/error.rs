@@ -1,6 +1,6 @@
 use std::{error::Error, fmt};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SchemeError {
     pub(crate) message: String,
     source: Option<&'static dyn Error>,
@@ -1,205 +1,1084 @@
 use crate::{
-    environment::{create_closure, Env},
+    analyzer::{analyze, If, Let, Node, RecordDefinition, Template},
+    compiler::Code,
+    environment::{create_closure, Env, Variable},
     error::SchemeError,
-    types::function::{Function, Lambda},
+    expander::expand,
+    gc::{self, Tracer},
+    types::condition::Condition,
+    types::continuation::Continuation,
+    types::function::{Control, Function, Lambda},
+    types::promise::{Promise, PromiseState},
+    types::record::{RecordProcedure, RecordType},
     types::{Exp, SpecialForm, Value},
 };
 use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::prelude::*;
 
-pub(crate) fn eval_all(input: &[Exp], env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let mut result = Exp::List(Vec::new());
+mod vm;
+
+/// How expanded expressions are run: walking the expression tree, or
+/// compiling it to bytecode for the virtual machine.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+    #[default]
+    Evaluator,
+    Vm,
+}
+
+/// The most frames the stack holds by default before calling a procedure
+/// raises an error, so runaway recursion fails instead of using up memory.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 100_000;
+
+/// Frames a handler for exceeding the maximum depth can use beyond it.
+const HANDLER_DEPTH: usize = 1_000;
+
+/// Expands and evaluates each top level expression in turn, so macros defined
+/// by one expression can be used by the next.
+pub(crate) fn eval_all(
+    input: &[Exp],
+    env: &mut Rc<RefCell<Env>>,
+    backend: Backend,
+    max_depth: usize,
+) -> Result<Exp, SchemeError> {
+    let mut result = Exp::new_list();
     for exp in input {
-        result = evaluate(exp, env)?;
+        let exp = expand(exp, env, max_depth)?;
+        let mut machine = Machine::new(max_depth);
+        result = match backend {
+            Backend::Evaluator => machine.run(State::Eval(analyze(&exp, env), env.clone()))?,
+            Backend::Vm => vm::execute(&mut machine, &exp, env)?,
+        };
+        gc::collect_if_due();
     }
     Ok(result)
 }
 
-pub(crate) fn evaluate(input: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    match input {
-        Exp::List(list) => {
-            validate_num_args("evalute", &list, 1, usize::MAX)?;
-            let operator = evaluate(list.get(0).unwrap(), env)?;
-            if let Exp::Atom(Value::SpecialForm(form)) = operator {
-                match form {
-                    SpecialForm::Define => do_define_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::Let => do_let_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::Lambda => do_lambda_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::If => do_if_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::And => do_and_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::Or => do_or_form(&Exp::from(&list[1..]), env),
-                    SpecialForm::Eval => {
-                        validate_num_args("eval", &list[1..], 1, 1)?;
-                        evaluate(&evaluate(list.get(1).unwrap(), env)?, env)
-                    }
-                    SpecialForm::Apply => {
-                        let apply_list = &list[1..];
-                        validate_num_args("apply", &apply_list, 2, 2)?;
-                        let operator = evaluate(apply_list.get(0).unwrap(), env)?;
-                        let args = evaluate(apply_list.get(1).unwrap(), env)?;
-                        apply(&operator, &args, env)
-                    }
+/*
+    The evaluator is a machine with an explicit continuation: evaluating a
+    subexpression pushes a frame recording what to do with its value, instead
+    of recursing on the Rust stack. Forms in tail position (if branches, the
+    last expression of and/or/begin, let bodies and lambda bodies) push no
+    frame, so tail calls run in constant space, and call/cc captures the continuation by
+    copying the frame stack along with the active dynamic-wind winders and
+    exception handlers.
+
+    Bytecode compiled for the virtual machine runs in Code frames on the same
+    stack, so compiled and tree-walked procedures can call each other and
+    continuations capture both.
+*/
+
+#[derive(Clone, Debug)]
+pub(crate) enum Frame {
+    // The operator of a call is being evaluated
+    Operator {
+        call: Rc<[Node]>,
+        env: Rc<RefCell<Env>>,
+    },
+    // The arguments of a call are being evaluated left to right, with values
+    // holding the ones evaluated so far
+    Args {
+        operator: Exp,
+        values: Vec<Exp>,
+        call: Rc<[Node]>,
+        env: Rc<RefCell<Env>>,
+    },
+    // The expressions of a body are being evaluated in turn, continuing with
+    // the one at next
+    Body {
+        body: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    },
+    // The test of an if is being evaluated
+    If {
+        node: Rc<If>,
+        env: Rc<RefCell<Env>>,
+    },
+    And {
+        args: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    },
+    Or {
+        args: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    },
+    Define {
+        variable: Variable,
+        env: Rc<RefCell<Env>>,
+    },
+    Set {
+        variable: Variable,
+        env: Rc<RefCell<Env>>,
+    },
+    Let {
+        node: Rc<Let>,
+        values: Vec<Exp>,
+        env: Rc<RefCell<Env>>,
+    },
+    // The defaults of the optional and keyword parameters not given an
+    // argument are being evaluated in the callee's frame
+    Default {
+        defaults: Rc<[(usize, Node)]>,
+        next: usize,
+        lambda: Lambda,
+        env: Rc<RefCell<Env>>,
+    },
+    // Bytecode waiting on a call, continuing at pc with the value pushed
+    Code {
+        code: Rc<Code>,
+        pc: usize,
+        env: Rc<RefCell<Env>>,
+    },
+    // The producer of a call-with-values has returned
+    Values {
+        consumer: Exp,
+        env: Rc<RefCell<Env>>,
+    },
+    // The expression of a promise being forced has returned
+    Force {
+        promise: Promise,
+    },
+    // The value is an expression to be expanded and evaluated
+    Eval {
+        env: Rc<RefCell<Env>>,
+    },
+    // The before thunk of a dynamic-wind has returned
+    WindBefore {
+        before: Exp,
+        thunk: Exp,
+        after: Exp,
+        env: Rc<RefCell<Env>>,
+    },
+    // The body thunk of a dynamic-wind has returned
+    WindAfter {
+        after: Exp,
+        outer: Winders,
+        env: Rc<RefCell<Env>>,
+    },
+    // Runs a before or after thunk while moving between dynamic extents
+    Wind {
+        thunk: Exp,
+        winders: Winders,
+        env: Rc<RefCell<Env>>,
+    },
+    // Returns a value saved before running before or after thunks
+    Restore {
+        value: Exp,
+        winders: Winders,
+    },
+    // Propagates an error once the after thunks being unwound have run
+    Raise {
+        error: SchemeError,
+    },
+    // Restores the handlers outside a with-exception-handler body or a call
+    // to a handler
+    Handlers {
+        handlers: Handlers,
+    },
+    // A handler has returned from a non-continuable raise
+    Raised {
+        obj: Exp,
+    },
+}
+
+impl Frame {
+    /// Finds the objects a frame of a captured continuation references.
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Frame::Operator { env, .. }
+            | Frame::Body { env, .. }
+            | Frame::If { env, .. }
+            | Frame::And { env, .. }
+            | Frame::Or { env, .. }
+            | Frame::Define { env, .. }
+            | Frame::Set { env, .. }
+            | Frame::Code { env, .. }
+            | Frame::Eval { env } => tracer.object(env),
+            Frame::Args {
+                operator,
+                values,
+                env,
+                ..
+            } => {
+                tracer.exp(operator);
+                values.iter().for_each(|value| tracer.exp(value));
+                tracer.object(env);
+            }
+            Frame::Let { values, env, .. } => {
+                values.iter().for_each(|value| tracer.exp(value));
+                tracer.object(env);
+            }
+            Frame::Default { lambda, env, .. } => {
+                tracer.lambda(lambda);
+                tracer.object(env);
+            }
+            Frame::Values { consumer, env } => {
+                tracer.exp(consumer);
+                tracer.object(env);
+            }
+            Frame::Force { promise } => promise.trace(tracer),
+            Frame::WindBefore {
+                before,
+                thunk,
+                after,
+                env,
+            } => {
+                for exp in [before, thunk, after] {
+                    tracer.exp(exp);
                 }
-            } else {
-                apply(&operator, &Exp::List(list[1..].to_vec()), env)
+                tracer.object(env);
             }
+            Frame::WindAfter { after, outer, env } => {
+                tracer.exp(after);
+                trace_winders(outer, tracer);
+                tracer.object(env);
+            }
+            Frame::Wind {
+                thunk,
+                winders,
+                env,
+            } => {
+                tracer.exp(thunk);
+                trace_winders(winders, tracer);
+                tracer.object(env);
+            }
+            Frame::Restore { value, winders } => {
+                tracer.exp(value);
+                trace_winders(winders, tracer);
+            }
+            Frame::Handlers { handlers } => trace_handlers(handlers, tracer),
+            Frame::Raised { obj } => tracer.exp(obj),
+            Frame::Raise { .. } => {}
+        }
+    }
+}
+
+/*
+    Winders - the before and after thunks of the dynamic-wind calls whose body
+    is currently running, innermost first
+*/
+
+pub(crate) type Winders = Option<Rc<Winder>>;
+
+#[derive(Debug)]
+pub(crate) struct Winder {
+    before: Exp,
+    after: Exp,
+    env: Rc<RefCell<Env>>,
+    depth: usize,
+    parent: Winders,
+}
+
+pub(crate) fn trace_winders(winders: &Winders, tracer: &mut Tracer) {
+    if let Some(winder) = winders {
+        tracer.owned(winder, |tracer, winder| {
+            tracer.exp(&winder.before);
+            tracer.exp(&winder.after);
+            tracer.object(&winder.env);
+            trace_winders(&winder.parent, tracer);
+        })
+    }
+}
+
+fn winders_depth(winders: &Winders) -> usize {
+    winders.as_ref().map_or(0, |winder| winder.depth)
+}
+
+/// The innermost winder shared by two sets of winders.
+fn common_winders(mut a: Winders, mut b: Winders) -> Winders {
+    while winders_depth(&a) > winders_depth(&b) {
+        a = a.and_then(|winder| winder.parent.clone());
+    }
+    while winders_depth(&b) > winders_depth(&a) {
+        b = b.and_then(|winder| winder.parent.clone());
+    }
+    while let (Some(x), Some(y)) = (&a, &b) {
+        if Rc::ptr_eq(x, y) {
+            break;
         }
-        Exp::Atom(atom) => match atom {
-            Value::Symbol(symbol) => Ok(env.borrow().get(symbol)?),
-            Value::Quote(quoted) => Ok(*quoted.clone()),
-            _ => Ok(Exp::Atom(atom.clone())),
-        },
-        Exp::ImpList(_) => todo!(),
+        a = x.parent.clone();
+        b = y.parent.clone();
+    }
+    a
+}
+
+/*
+    Handlers - the exception handlers installed by with-exception-handler,
+    innermost first
+*/
+
+pub(crate) type Handlers = Option<Rc<Handler>>;
+
+#[derive(Debug)]
+pub(crate) struct Handler {
+    handler: Exp,
+    env: Rc<RefCell<Env>>,
+    parent: Handlers,
+}
+
+pub(crate) fn trace_handlers(handlers: &Handlers, tracer: &mut Tracer) {
+    if let Some(handler) = handlers {
+        tracer.owned(handler, |tracer, handler| {
+            tracer.exp(&handler.handler);
+            tracer.object(&handler.env);
+            trace_handlers(&handler.parent, tracer);
+        })
+    }
+}
+
+/// A value passed to a continuation taking exactly one, which multiple values
+/// can't be.
+fn single(value: Exp) -> Result<Exp, SchemeError> {
+    match value {
+        Exp::Atom(Value::Values(values)) => Err(SchemeError::new(format!(
+            "Expected a single value, found {} values",
+            values.len()
+        ))),
+        value => Ok(value),
     }
 }
 
-pub(crate) fn eval_args(input: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let mut results = Vec::new();
-    for exp in input.unwrap_list()? {
-        results.push(evaluate(&exp, env)?);
+/// The error reported when nothing handles a raised object.
+fn uncaught(obj: &Exp) -> SchemeError {
+    match obj {
+        Exp::Atom(Value::Condition(condition)) => SchemeError::new(condition.report()),
+        _ => SchemeError::new(format!("Uncaught exception {}", obj)),
     }
-    Ok(Exp::List(results))
 }
 
-pub(crate) fn apply(
+enum State {
+    Eval(Node, Rc<RefCell<Env>>),
+    Return(Exp),
+    // Runs bytecode from pc
+    Run(Rc<Code>, usize, Rc<RefCell<Env>>),
+}
+
+struct Machine {
+    stack: Vec<Frame>,
+    winders: Winders,
+    handlers: Handlers,
+    // The operands of the bytecode being run, shared by every Code frame
+    values: Vec<Exp>,
+    max_depth: usize,
+    // The depth calling a procedure raises an error at, raised past the
+    // maximum while a handler for exceeding it runs
+    depth_limit: usize,
+}
+
+/// Analyzes and evaluates an expanded expression.
+pub(crate) fn evaluate(
+    input: &Exp,
+    env: &mut Rc<RefCell<Env>>,
+    max_depth: usize,
+) -> Result<Exp, SchemeError> {
+    Machine::new(max_depth).run(State::Eval(analyze(input, env), env.clone()))
+}
+
+/// Calls a function with already evaluated arguments and evaluates the result.
+pub(crate) fn call(
     operator: &Exp,
     args: &Exp,
     env: &mut Rc<RefCell<Env>>,
+    max_depth: usize,
 ) -> Result<Exp, SchemeError> {
-    if let Value::Function(mut function) = operator.unwrap_atom()? {
-        let args = eval_args(args, env)?;
-        function.call(&args, env)
-    } else {
-        Err(SchemeError::new(format!(
-            "Expected a function, found {}",
-            operator
-        )))
-    }
-}
-
-fn do_define_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    validate_num_args("define", &args, 1, usize::MAX)?;
-    let second = &args[0];
-    match second {
-        Exp::List(signature) => {
-            validate_num_args("define signature", signature, 1, usize::MAX)?;
-            if let Value::Symbol(name) = signature.get(0).unwrap().unwrap_atom()? {
-                let params = Exp::List(signature[1..].to_vec());
-                let lambda_form_args = Exp::from(&vec![&[params][..], &args[1..]].concat()[..]);
-                let lambda = do_lambda_form(&lambda_form_args, env)?;
-                env.borrow_mut().set(&name, &lambda);
-                return Ok(Exp::new_list());
-            } else {
-                return Err(SchemeError::new(format!(
-                    "Expected a symbol as the name, found {}",
-                    signature[0]
-                )));
-            }
-        }
-        Exp::ImpList(_) => todo!(),
-        Exp::Atom(val) => {
-            validate_num_args("define value", &args, 2, 2)?;
-            if let Value::Symbol(symbol) = val {
-                let third = evaluate(&args[1], env)?;
-                env.borrow_mut().set(&symbol, &third);
-                return Ok(Exp::new_list());
-            }
-        }
-    }
-    Err(SchemeError::new(format!(
-        "Expected a symbol as the name, found {}",
-        second
-    )))
-}
-
-fn do_let_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    validate_num_args("let", &args, 2, usize::MAX)?;
-    let mut closure = create_closure(env.clone());
-
-    if let Exp::List(pairs) = &args[0] {
-        for pair in pairs {
-            if let Exp::List(pair_vec) = pair {
-                validate_num_args("let pair", pair_vec, 2, usize::MAX)?;
-                if let Value::Symbol(name) = pair_vec.get(0).unwrap().unwrap_atom()? {
-                    let value = evaluate(pair_vec.get(1).unwrap(), &mut closure)?;
-                    closure.borrow_mut().set(&name, &value);
+    let mut machine = Machine::new(max_depth);
+    let state = machine.apply(operator.clone(), args.unwrap_list()?, env.clone())?;
+    machine.run(state)
+}
+
+impl Machine {
+    fn new(max_depth: usize) -> Self {
+        Machine {
+            stack: Vec::new(),
+            winders: None,
+            handlers: None,
+            values: Vec::new(),
+            max_depth,
+            depth_limit: max_depth,
+        }
+    }
+
+    fn run(&mut self, mut state: State) -> Result<Exp, SchemeError> {
+        loop {
+            let next = match state {
+                State::Eval(exp, env) => self.eval(exp, env),
+                State::Return(value) => match self.stack.pop() {
+                    Some(frame) => self.resume(frame, value),
+                    None => return Ok(value),
+                },
+                State::Run(code, pc, env) => self.execute(code, pc, env),
+            };
+            state = match next {
+                Ok(state) => state,
+                Err(error) => self.recover(error)?,
+            };
+        }
+    }
+
+    /// Raises an error as a condition if there is a handler for it. Otherwise
+    /// runs the after thunks of any active winders before the error
+    /// propagates out of the evaluator.
+    fn recover(&mut self, error: SchemeError) -> Result<State, SchemeError> {
+        if self.handlers.is_some() {
+            let condition = Exp::Atom(Value::Condition(Condition::from(error)));
+            return self
+                .raise(condition, false)
+                .or_else(|error| self.recover(error));
+        }
+        if self.winders.is_none() {
+            return Err(error);
+        }
+        self.stack = vec![Frame::Raise { error }];
+        self.values.clear();
+        self.wind_to(&None);
+        Ok(State::Return(Exp::new_list()))
+    }
+
+    /// Pushes frames that run the after thunks of the winders being left,
+    /// innermost first, then the before thunks of the winders being entered,
+    /// outermost first. Each thunk runs with the winders outside its own.
+    fn wind_to(&mut self, target: &Winders) {
+        let common = common_winders(self.winders.clone(), target.clone());
+
+        let mut entering = target.clone();
+        while winders_depth(&entering) > winders_depth(&common) {
+            let winder = entering.unwrap();
+            self.stack.push(Frame::Wind {
+                thunk: winder.before.clone(),
+                winders: winder.parent.clone(),
+                env: winder.env.clone(),
+            });
+            entering = winder.parent.clone();
+        }
+
+        let mut leaving = Vec::new();
+        let mut current = self.winders.clone();
+        while winders_depth(&current) > winders_depth(&common) {
+            let winder = current.unwrap();
+            current = winder.parent.clone();
+            leaving.push(winder);
+        }
+        for winder in leaving.into_iter().rev() {
+            self.stack.push(Frame::Wind {
+                thunk: winder.after.clone(),
+                winders: winder.parent.clone(),
+                env: winder.env.clone(),
+            });
+        }
+    }
+
+    fn eval(&mut self, node: Node, env: Rc<RefCell<Env>>) -> Result<State, SchemeError> {
+        Ok(match node {
+            Node::Const(value) => State::Return(value),
+            Node::Get(variable) => State::Return(env.borrow().lookup(&variable)?),
+            Node::Define(variable, value) => {
+                self.stack.push(Frame::Define {
+                    variable,
+                    env: env.clone(),
+                });
+                State::Eval((*value).clone(), env)
+            }
+            Node::Set(variable, value) => {
+                self.stack.push(Frame::Set {
+                    variable,
+                    env: env.clone(),
+                });
+                State::Eval((*value).clone(), env)
+            }
+            Node::If(node) => {
+                let test = node.test.clone();
+                self.stack.push(Frame::If {
+                    node,
+                    env: env.clone(),
+                });
+                State::Eval(test, env)
+            }
+            Node::Lambda(template) => State::Return(make_lambda(template, env)),
+            Node::CaseLambda(templates) => {
+                let clauses = templates
+                    .iter()
+                    .map(|template| Lambda {
+                        template: template.clone(),
+                        env: env.clone(),
+                        code: None,
+                    })
+                    .collect();
+                State::Return(Exp::Atom(Value::Function(Function::CaseLambda(clauses))))
+            }
+            Node::Delay(template, lazy) => {
+                let thunk = Lambda {
+                    template,
+                    env,
+                    code: None,
+                };
+                State::Return(Exp::Atom(Value::Promise(Promise::new(
+                    PromiseState::Delayed { thunk, lazy },
+                ))))
+            }
+            Node::Begin(body) => self.eval_body(body, 0, env)?,
+            Node::And(args) => self.eval_and(args, 0, env)?,
+            Node::Or(args) => self.eval_or(args, 0, env)?,
+            Node::Let(node) => self.eval_let_bindings(node, Vec::new(), env)?,
+            Node::Call(call) | Node::Apply(call) => {
+                let operator = call[0].clone();
+                self.stack.push(Frame::Operator {
+                    call,
+                    env: env.clone(),
+                });
+                State::Eval(operator, env)
+            }
+            Node::Eval(exp) => {
+                self.stack.push(Frame::Eval { env: env.clone() });
+                State::Eval((*exp).clone(), env)
+            }
+            Node::DefineRecordType(definition) => {
+                State::Return(define_record_type(&definition, &env)?)
+            }
+            Node::Error(error) => return Err(error),
+        })
+    }
+
+    /// Continues the computation recorded by a frame with the value of the
+    /// expression it was waiting on.
+    fn resume(&mut self, frame: Frame, value: Exp) -> Result<State, SchemeError> {
+        // Bodies discard the values they are given and the rest pass them on,
+        // while compiled code checks them as it uses them
+        let value = match frame {
+            Frame::Operator { .. }
+            | Frame::Args { .. }
+            | Frame::If { .. }
+            | Frame::And { .. }
+            | Frame::Or { .. }
+            | Frame::Define { .. }
+            | Frame::Set { .. }
+            | Frame::Let { .. }
+            | Frame::Default { .. }
+            | Frame::Eval { .. } => single(value)?,
+            _ => value,
+        };
+        match frame {
+            Frame::Operator { call, env } => {
+                let values = Vec::with_capacity(call.len() - 1);
+                self.eval_args(value, values, call, env)
+            }
+            Frame::Args {
+                operator,
+                mut values,
+                call,
+                env,
+            } => {
+                values.push(value);
+                self.eval_args(operator, values, call, env)
+            }
+            Frame::Body { body, next, env } => self.eval_body(body, next, env),
+            Frame::If { node, env } => match (value, &node.alternative) {
+                (Exp::Atom(Value::Boolean(false)), Some(alternative)) => {
+                    Ok(State::Eval(alternative.clone(), env))
+                }
+                (Exp::Atom(Value::Boolean(false)), None) => Ok(State::Return(Exp::new_list())),
+                _ => Ok(State::Eval(node.consequent.clone(), env)),
+            },
+            Frame::And { args, next, env } => match value {
+                Exp::Atom(Value::Boolean(false)) => Ok(State::Return(value)),
+                _ => self.eval_and(args, next, env),
+            },
+            Frame::Or { args, next, env } => match value {
+                Exp::Atom(Value::Boolean(false)) => self.eval_or(args, next, env),
+                _ => Ok(State::Return(value)),
+            },
+            Frame::Define { variable, env } => {
+                env.borrow_mut().define(&variable, &value);
+                Ok(State::Return(Exp::new_list()))
+            }
+            Frame::Set { variable, env } => {
+                env.borrow_mut().assign(&variable, &value)?;
+                Ok(State::Return(Exp::new_list()))
+            }
+            Frame::Let {
+                node,
+                mut values,
+                env,
+            } => {
+                values.push(value);
+                self.eval_let_bindings(node, values, env)
+            }
+            Frame::Default {
+                defaults,
+                next,
+                lambda,
+                env,
+            } => {
+                env.borrow_mut().set_slot(defaults[next - 1].0, &value);
+                self.eval_defaults(defaults, next, lambda, env)
+            }
+            Frame::Code { code, pc, env } => {
+                self.values.push(value);
+                Ok(State::Run(code, pc, env))
+            }
+            Frame::Values { consumer, env } => self.apply(consumer, value.into_values(), env),
+            Frame::Force { promise } => match (promise.state(), value) {
+                // The promise was forced again while computing its value
+                (PromiseState::Done(value), _) => Ok(State::Return(value)),
+                (PromiseState::Delayed { lazy: true, .. }, Exp::Atom(Value::Promise(inner))) => {
+                    promise.adopt(&inner);
+                    self.force(Exp::Atom(Value::Promise(promise)))
                 }
+                (_, value) => {
+                    promise.set_state(PromiseState::Done(value.clone()));
+                    Ok(State::Return(value))
+                }
+            },
+            Frame::Eval { env } => Ok(State::Eval(
+                analyze(&expand(&value, &env, self.max_depth)?, &env),
+                env,
+            )),
+            Frame::WindBefore {
+                before,
+                thunk,
+                after,
+                env,
+            } => {
+                let outer = self.winders.take();
+                self.winders = Some(Rc::new(Winder {
+                    before,
+                    after: after.clone(),
+                    env: env.clone(),
+                    depth: winders_depth(&outer) + 1,
+                    parent: outer.clone(),
+                }));
+                self.stack.push(Frame::WindAfter {
+                    after,
+                    outer,
+                    env: env.clone(),
+                });
+                self.apply(thunk, Vec::new(), env)
+            }
+            Frame::WindAfter { after, outer, env } => {
+                self.winders = outer.clone();
+                self.stack.push(Frame::Restore {
+                    value,
+                    winders: outer,
+                });
+                self.apply(after, Vec::new(), env)
             }
+            Frame::Wind {
+                thunk,
+                winders,
+                env,
+            } => {
+                self.winders = winders;
+                self.apply(thunk, Vec::new(), env)
+            }
+            Frame::Restore { value, winders } => {
+                self.winders = winders;
+                Ok(State::Return(value))
+            }
+            Frame::Raise { error } => Err(error),
+            Frame::Handlers { handlers } => {
+                self.handlers = handlers;
+                Ok(State::Return(value))
+            }
+            Frame::Raised { obj } => Err(SchemeError::new(format!(
+                "Exception handler returned from non-continuable raise of {}",
+                obj
+            ))),
         }
-    } else {
-        return Err(SchemeError::new(
-            "Let expects a list of definitions".to_string(),
-        ));
     }
-    eval_all(&args[1..], &mut closure)
-}
 
-fn do_lambda_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    validate_num_args("lambda", &args, 2, usize::MAX)?;
-    let params = match &args[0] {
-        Exp::List(param_list) => eval_param_list(param_list)?,
-        Exp::ImpList(_) => todo!(),
-        Exp::Atom(_) => todo!(), // treat as vararg?? seems to be what guile does
-    };
-    let body = args[1..].to_vec();
-    let env = create_closure(env.clone());
-    Ok(Exp::Atom(Value::Function(Function::Lambda(Lambda {
-        params,
-        body,
-        env,
-    }))))
-}
+    /// Evaluates the next argument of a call, or applies the operator once
+    /// they have all been evaluated. The arguments of apply are evaluated the
+    /// same way before the function is applied to the list.
+    fn eval_args(
+        &mut self,
+        operator: Exp,
+        mut values: Vec<Exp>,
+        call: Rc<[Node]>,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        if let Some(arg) = call.get(values.len() + 1) {
+            let arg = arg.clone();
+            self.stack.push(Frame::Args {
+                operator,
+                values,
+                call,
+                env: env.clone(),
+            });
+            return Ok(State::Eval(arg, env));
+        }
+        match operator {
+            Exp::Atom(Value::SpecialForm(SpecialForm::Apply)) => {
+                let args = values.pop().unwrap().unwrap_list()?;
+                let operator = values.pop().unwrap();
+                self.apply(operator, args, env)
+            }
+            _ => self.apply(operator, values, env),
+        }
+    }
 
-fn eval_param_list(param_list: &Vec<Exp>) -> Result<Vec<String>, SchemeError> {
-    Ok(param_list
-        .iter()
-        .map(|arg| {
-            let atom = arg.unwrap_atom()?;
-            match atom {
-                Value::Symbol(name) => Ok(name.to_string()),
-                _ => Err(SchemeError::new(format!(
-                    "Parameter list expects symbols, found {}",
-                    atom
-                ))),
+    /// Applies a function to already evaluated arguments. Builtins return
+    /// their result immediately, lambdas continue with their body in a new
+    /// frame once any missing defaults have been evaluated, and continuations replace the stack with the one they captured
+    /// after running any before and after thunks needed to get there.
+    fn apply(
+        &mut self,
+        operator: Exp,
+        args: Vec<Exp>,
+        mut env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        match operator {
+            Exp::Atom(Value::Function(Function::Builtin(builtin))) => {
+                Ok(State::Return((builtin.func)(&Exp::list(args), &mut env)?))
             }
-        })
-        .collect::<Result<Vec<String>, SchemeError>>()?)
-}
+            Exp::Atom(Value::Function(Function::Lambda(lambda))) => self.apply_lambda(lambda, args),
+            Exp::Atom(Value::Function(Function::CaseLambda(clauses))) => {
+                let num_args = args.len();
+                match clauses
+                    .into_iter()
+                    .find(|lambda| lambda.template.params.accepts(num_args))
+                {
+                    Some(lambda) => self.apply_lambda(lambda, args),
+                    None => Err(SchemeError::new(format!(
+                        "case-lambda has no clause accepting {} args",
+                        num_args
+                    ))),
+                }
+            }
+            Exp::Atom(Value::Function(Function::Control(control))) => {
+                self.apply_control(control, args, env)
+            }
+            Exp::Atom(Value::Function(Function::Record(procedure))) => {
+                Ok(State::Return(procedure.call(args)?))
+            }
+            Exp::Atom(Value::Continuation(continuation)) => {
+                // Passing other than one value is returning multiple values
+                let value = Exp::from_values(args);
+                self.handlers = continuation.handlers.clone();
+                self.stack = continuation.frames.to_vec();
+                self.values = continuation.values.to_vec();
+                self.stack.push(Frame::Restore {
+                    value,
+                    winders: continuation.winders.clone(),
+                });
+                self.wind_to(&continuation.winders);
+                Ok(State::Return(Exp::new_list()))
+            }
+            _ => Err(SchemeError::new(format!(
+                "Expected a function, found {}",
+                operator
+            ))),
+        }
+    }
 
-fn do_if_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    validate_num_args("if", &args, 3, 3)?;
-    let condition = evaluate(&args[0], env)?.unwrap_atom()?;
-    if let Value::Boolean(false) = condition {
-        return evaluate(&args[2], env);
+    fn apply_lambda(&mut self, lambda: Lambda, args: Vec<Exp>) -> Result<State, SchemeError> {
+        // Everything the machine holds counts as referenced from outside the
+        // heap, so collecting mid-run keeps it, and loops within one
+        // expression don't pile up garbage
+        gc::collect_if_due();
+        self.check_depth(&lambda)?;
+        let (closure, defaults) = lambda.bind_args(args)?;
+        self.eval_defaults(Rc::from(defaults), 0, lambda, closure)
     }
-    evaluate(&args[1], env)
-}
 
-fn do_and_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    let mut val = Value::Boolean(true);
-    for a in args {
-        val = evaluate(&a, env)?.unwrap_atom()?;
-        if val == Value::Boolean(false) {
-            break;
+    /// Raises an error if the stack is too deep to call another procedure.
+    /// Handlers for the error get room to run, until the stack is back under
+    /// the maximum.
+    fn check_depth(&mut self, lambda: &Lambda) -> Result<(), SchemeError> {
+        let depth = self.stack.len();
+        if depth < self.max_depth {
+            self.depth_limit = self.max_depth;
+        } else if depth >= self.depth_limit {
+            self.depth_limit = depth + HANDLER_DEPTH;
+            return Err(SchemeError::new(format!(
+                "Maximum recursion depth exceeded in {}",
+                match lambda.template.name {
+                    Some(name) => name.to_string(),
+                    None => "an anonymous procedure".to_string(),
+                }
+            )));
         }
+        Ok(())
     }
-    Ok(Exp::Atom(val))
-}
 
-fn do_or_form(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    let mut val = Value::Boolean(false);
-    for a in args {
-        val = evaluate(&a, env)?.unwrap_atom()?;
-        if val != Value::Boolean(false) {
-            break;
+    /// Evaluates the next missing default in the callee's frame, so it can
+    /// refer to the parameters before it, then continues with the body.
+    /// Defaults are always walked, even for lambdas with a compiled body.
+    fn eval_defaults(
+        &mut self,
+        defaults: Rc<[(usize, Node)]>,
+        next: usize,
+        lambda: Lambda,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        if next == defaults.len() {
+            return match lambda.code {
+                Some(code) => Ok(State::Run(code, 0, env)),
+                None => self.eval_body(lambda.template.body.clone(), 0, env),
+            };
+        }
+        let exp = defaults[next].1.clone();
+        self.stack.push(Frame::Default {
+            defaults,
+            next: next + 1,
+            lambda,
+            env: env.clone(),
+        });
+        Ok(State::Eval(exp, env))
+    }
+
+    fn apply_control(
+        &mut self,
+        control: Control,
+        args: Vec<Exp>,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        match control {
+            Control::CallCC => {
+                validate_num_args(&control.to_string(), &args, 1, 1)?;
+                let continuation = Continuation::new(
+                    self.stack.clone(),
+                    self.values.clone(),
+                    self.winders.clone(),
+                    self.handlers.clone(),
+                );
+                let function = args.into_iter().next().unwrap();
+                self.apply(
+                    function,
+                    vec![Exp::Atom(Value::Continuation(continuation))],
+                    env,
+                )
+            }
+            Control::DynamicWind => {
+                validate_num_args(&control.to_string(), &args, 3, 3)?;
+                let mut args = args.into_iter();
+                let (before, thunk, after) = (
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                );
+                self.stack.push(Frame::WindBefore {
+                    before: before.clone(),
+                    thunk,
+                    after,
+                    env: env.clone(),
+                });
+                self.apply(before, Vec::new(), env)
+            }
+            Control::CallWithValues => {
+                validate_num_args(&control.to_string(), &args, 2, 2)?;
+                let mut args = args.into_iter();
+                let (producer, consumer) = (args.next().unwrap(), args.next().unwrap());
+                self.stack.push(Frame::Values {
+                    consumer,
+                    env: env.clone(),
+                });
+                self.apply(producer, Vec::new(), env)
+            }
+            Control::Force => {
+                validate_num_args(&control.to_string(), &args, 1, 1)?;
+                self.force(args.into_iter().next().unwrap())
+            }
+            Control::WithExceptionHandler => {
+                validate_num_args(&control.to_string(), &args, 2, 2)?;
+                let mut args = args.into_iter();
+                let (handler, thunk) = (args.next().unwrap(), args.next().unwrap());
+                let outer = self.handlers.take();
+                self.stack.push(Frame::Handlers {
+                    handlers: outer.clone(),
+                });
+                self.handlers = Some(Rc::new(Handler {
+                    handler,
+                    env: env.clone(),
+                    parent: outer,
+                }));
+                self.apply(thunk, Vec::new(), env)
+            }
+            Control::Raise | Control::RaiseContinuable => {
+                validate_num_args(&control.to_string(), &args, 1, 1)?;
+                let obj = args.into_iter().next().unwrap();
+                self.raise(obj, control == Control::RaiseContinuable)
+            }
+            Control::Error => {
+                validate_num_args(&control.to_string(), &args, 1, usize::MAX)?;
+                let message = match &args[0] {
+                    Exp::Atom(Value::String(message)) => message.to_string(),
+                    message => message.to_string(),
+                };
+                let condition = Condition {
+                    message,
+                    irritants: args[1..].to_vec(),
+                };
+                self.raise(Exp::Atom(Value::Condition(condition)), false)
+            }
+        }
+    }
+
+    /// Returns the value of a promise, computing it if it isn't done yet.
+    /// Forcing anything else returns it unchanged.
+    fn force(&mut self, obj: Exp) -> Result<State, SchemeError> {
+        let promise = match obj {
+            Exp::Atom(Value::Promise(promise)) => promise,
+            value => return Ok(State::Return(value)),
+        };
+        match promise.state() {
+            PromiseState::Delayed { thunk, .. } => {
+                self.stack.push(Frame::Force { promise });
+                self.apply_lambda(thunk, Vec::new())
+            }
+            PromiseState::Done(value) => Ok(State::Return(value)),
+            PromiseState::Forward(_) => unreachable!("promise states are resolved"),
         }
     }
-    Ok(Exp::Atom(val))
+
+    /// Calls the innermost handler with a raised object, in the dynamic
+    /// environment of the raise except with the outer handlers installed. A
+    /// continuable raise returns what the handler returns, otherwise returning
+    /// from the handler is an error.
+    fn raise(&mut self, obj: Exp, continuable: bool) -> Result<State, SchemeError> {
+        let handler = match &self.handlers {
+            Some(handler) => handler.clone(),
+            None => return Err(uncaught(&obj)),
+        };
+        self.stack.push(match continuable {
+            true => Frame::Handlers {
+                handlers: self.handlers.clone(),
+            },
+            false => Frame::Raised { obj: obj.clone() },
+        });
+        self.handlers = handler.parent.clone();
+        self.apply(handler.handler.clone(), vec![obj], handler.env.clone())
+    }
+
+    /// Evaluates the expressions of a body in turn from next, leaving the last
+    /// one in tail position.
+    fn eval_body(
+        &mut self,
+        body: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        match body.len() - next {
+            0 => Ok(State::Return(Exp::new_list())),
+            1 => Ok(State::Eval(body[next].clone(), env)),
+            _ => {
+                let exp = body[next].clone();
+                self.stack.push(Frame::Body {
+                    body,
+                    next: next + 1,
+                    env: env.clone(),
+                });
+                Ok(State::Eval(exp, env))
+            }
+        }
+    }
+
+    /// Evaluates the arguments in turn from next, short circuiting on false,
+    /// with the last one in tail position.
+    fn eval_and(
+        &mut self,
+        args: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        match args.len() - next {
+            0 => Ok(State::Return(Exp::Atom(Value::Boolean(true)))),
+            1 => Ok(State::Eval(args[next].clone(), env)),
+            _ => {
+                let exp = args[next].clone();
+                self.stack.push(Frame::And {
+                    args,
+                    next: next + 1,
+                    env: env.clone(),
+                });
+                Ok(State::Eval(exp, env))
+            }
+        }
+    }
+
+    /// Evaluates the arguments in turn from next, short circuiting on a true
+    /// value, with the last one in tail position.
+    fn eval_or(
+        &mut self,
+        args: Rc<[Node]>,
+        next: usize,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        match args.len() - next {
+            0 => Ok(State::Return(Exp::Atom(Value::Boolean(false)))),
+            1 => Ok(State::Eval(args[next].clone(), env)),
+            _ => {
+                let exp = args[next].clone();
+                self.stack.push(Frame::Or {
+                    args,
+                    next: next + 1,
+                    env: env.clone(),
+                });
+                Ok(State::Eval(exp, env))
+            }
+        }
+    }
+
+    /// Evaluates the value of the next binding in the enclosing environment.
+    /// Once they have all been evaluated, binds them together in a new frame
+    /// and continues with the body.
+    fn eval_let_bindings(
+        &mut self,
+        node: Rc<Let>,
+        values: Vec<Exp>,
+        env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        if values.len() == node.values.len() {
+            let slots = values.into_iter().map(Some).collect();
+            let closure = create_closure(env, node.frame.clone(), slots);
+            return self.eval_body(node.body.clone(), 0, closure);
+        }
+        let exp = node.values[values.len()].clone();
+        self.stack.push(Frame::Let {
+            node,
+            values,
+            env: env.clone(),
+        });
+        Ok(State::Eval(exp, env))
+    }
+}
+
+/// Makes a lambda closing over the environment.
+fn make_lambda(template: Rc<Template>, env: Rc<RefCell<Env>>) -> Exp {
+    Exp::Atom(Value::Function(Function::Lambda(Lambda {
+        template,
+        env,
+        code: None,
+    })))
+}
+
+/// Defines a new record type along with its procedures.
+pub(crate) fn define_record_type(
+    definition: &RecordDefinition,
+    env: &Rc<RefCell<Env>>,
+) -> Result<Exp, SchemeError> {
+    let record_type = Rc::new(RecordType {
+        name: definition.name.name().to_string(),
+        fields: definition.fields.clone(),
+    });
+    env.borrow_mut().define(
+        &definition.name,
+        &Exp::Atom(Value::RecordType(record_type.clone())),
+    );
+    for (variable, operation) in &definition.procedures {
+        let procedure = RecordProcedure {
+            name: variable.name().to_string(),
+            record_type: record_type.clone(),
+            operation: operation.clone(),
+        };
+        env.borrow_mut().define(
+            variable,
+            &Exp::Atom(Value::Function(Function::Record(procedure))),
+        );
+    }
+    Ok(Exp::new_list())
 }
 
 pub(crate) fn validate_num_args<T>(
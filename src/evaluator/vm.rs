@@ -0,0 +1,231 @@
+use super::{define_record_type, single, Frame, Machine, State};
+use crate::{
+    analyzer::analyze,
+    compiler::{compile, Code, Op},
+    environment::{create_closure, Env},
+    error::SchemeError,
+    expander::expand,
+    types::function::{Function, Lambda},
+    types::promise::{Promise, PromiseState},
+    types::{Exp, Value},
+};
+use std::{cell::RefCell, rc::Rc};
+
+/*
+    Virtual machine - runs compiled code on the evaluator's machine. The code
+    being run is held in locals rather than a frame, with its operands on the
+    machine's value stack; a call pushes a Code frame to come back to unless
+    it is a tail call. Builtins are called in place, and calls to compiled
+    lambdas and returns to compiled code switch code without going back
+    through the machine's loop.
+*/
+
+/// Analyzes, compiles and runs an expanded expression on a machine.
+pub(super) fn execute(
+    machine: &mut Machine,
+    exp: &Exp,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Exp, SchemeError> {
+    machine.run(State::Run(compile(&analyze(exp, env)), 0, env.clone()))
+}
+
+fn is_false(value: &Exp) -> bool {
+    matches!(value, Exp::Atom(Value::Boolean(false)))
+}
+
+impl Machine {
+    pub(super) fn execute(
+        &mut self,
+        mut code: Rc<Code>,
+        mut pc: usize,
+        mut env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        loop {
+            let op = code.ops[pc];
+            pc += 1;
+            let state = match op {
+                Op::Const(index) => {
+                    self.values.push(code.constants[index].clone());
+                    continue;
+                }
+                Op::Get(index) => {
+                    let value = env.borrow().lookup(&code.variables[index])?;
+                    self.values.push(value);
+                    continue;
+                }
+                Op::Define(index) => {
+                    let value = single(self.pop())?;
+                    env.borrow_mut().define(&code.variables[index], &value);
+                    self.values.push(Exp::new_list());
+                    continue;
+                }
+                Op::Set(index) => {
+                    let value = single(self.pop())?;
+                    env.borrow_mut().assign(&code.variables[index], &value)?;
+                    self.values.push(Exp::new_list());
+                    continue;
+                }
+                Op::Pop => {
+                    self.pop();
+                    continue;
+                }
+                Op::Jump(target) => {
+                    pc = target;
+                    continue;
+                }
+                Op::JumpIfFalse(target) => {
+                    if is_false(&single(self.pop())?) {
+                        pc = target;
+                    }
+                    continue;
+                }
+                Op::AndJump(target) | Op::OrJump(target) => {
+                    let and = matches!(op, Op::AndJump(_));
+                    let value = single(self.pop())?;
+                    if is_false(&value) == and {
+                        self.values.push(value);
+                        pc = target;
+                    }
+                    continue;
+                }
+                Op::Lambda(index) => {
+                    let lambda = make_lambda(&code, index, &env);
+                    self.values
+                        .push(Exp::Atom(Value::Function(Function::Lambda(lambda))));
+                    continue;
+                }
+                Op::CaseLambda(start, count) => {
+                    let clauses = (start..start + count)
+                        .map(|index| make_lambda(&code, index, &env))
+                        .collect();
+                    self.values
+                        .push(Exp::Atom(Value::Function(Function::CaseLambda(clauses))));
+                    continue;
+                }
+                Op::Delay(index, lazy) => {
+                    let thunk = make_lambda(&code, index, &env);
+                    self.values.push(Exp::Atom(Value::Promise(Promise::new(
+                        PromiseState::Delayed { thunk, lazy },
+                    ))));
+                    continue;
+                }
+                Op::DefineRecordType(index) => {
+                    let value = define_record_type(&code.records[index], &env)?;
+                    self.values.push(value);
+                    continue;
+                }
+                Op::Let(index) | Op::TailLet(index) => {
+                    let (node, body) = &code.lets[index];
+                    let values = self.values.split_off(self.values.len() - node.values.len());
+                    let slots = values
+                        .into_iter()
+                        .map(|value| single(value).map(Some))
+                        .collect::<Result<_, _>>()?;
+                    let closure = create_closure(env.clone(), node.frame.clone(), slots);
+                    let body = body.clone();
+                    if let Op::Let(_) = op {
+                        self.stack.push(Frame::Code { code, pc, env });
+                    }
+                    State::Run(body, 0, closure)
+                }
+                Op::Call(num_args) | Op::TailCall(num_args) => {
+                    let args = self.values.split_off(self.values.len() - num_args);
+                    let args = args.into_iter().map(single).collect::<Result<_, _>>()?;
+                    let operator = single(self.pop())?;
+                    self.call(operator, args, op, code, pc, env)?
+                }
+                Op::Apply | Op::TailApply => {
+                    let args = single(self.pop())?.unwrap_list()?;
+                    let operator = single(self.pop())?;
+                    self.call(operator, args, op, code, pc, env)?
+                }
+                Op::Eval => {
+                    let node = analyze(&expand(&single(self.pop())?, &env, self.max_depth)?, &env);
+                    self.stack.push(Frame::Code {
+                        code,
+                        pc,
+                        env: env.clone(),
+                    });
+                    State::Run(compile(&node), 0, env)
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    self.return_value(value)
+                }
+                Op::Error(index) => return Err(code.errors[index].clone()),
+            };
+            match state {
+                State::Run(next, next_pc, next_env) => {
+                    code = next;
+                    pc = next_pc;
+                    env = next_env;
+                }
+                state => return Ok(state),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Exp {
+        self.values
+            .pop()
+            .expect("the operands of an op are pushed before it")
+    }
+
+    /// Calls a function from compiled code, coming back to the code after the
+    /// call unless it is a tail call.
+    fn call(
+        &mut self,
+        operator: Exp,
+        args: Vec<Exp>,
+        op: Op,
+        code: Rc<Code>,
+        pc: usize,
+        mut env: Rc<RefCell<Env>>,
+    ) -> Result<State, SchemeError> {
+        let tail = matches!(op, Op::TailCall(_) | Op::TailApply);
+        if let Exp::Atom(Value::Function(Function::Builtin(builtin))) = &operator {
+            let value = (builtin.func)(&Exp::list(args), &mut env)?;
+            if tail {
+                return Ok(self.return_value(value));
+            }
+            self.values.push(value);
+            return Ok(State::Run(code, pc, env));
+        }
+        if !tail {
+            self.stack.push(Frame::Code {
+                code,
+                pc,
+                env: env.clone(),
+            });
+        }
+        Ok(match self.apply(operator, args, env)? {
+            State::Return(value) => self.return_value(value),
+            state => state,
+        })
+    }
+
+    /// Returns a value to the frame below, continuing in place if it is
+    /// waiting compiled code.
+    fn return_value(&mut self, value: Exp) -> State {
+        match self.stack.last() {
+            Some(Frame::Code { .. }) => match self.stack.pop() {
+                Some(Frame::Code { code, pc, env }) => {
+                    self.values.push(value);
+                    State::Run(code, pc, env)
+                }
+                _ => unreachable!("the last frame is code"),
+            },
+            _ => State::Return(value),
+        }
+    }
+}
+
+/// Makes a lambda from a template, closing over the environment.
+fn make_lambda(code: &Code, index: usize, env: &Rc<RefCell<Env>>) -> Lambda {
+    let (template, code) = &code.templates[index];
+    Lambda {
+        template: template.clone(),
+        env: env.clone(),
+        code: Some(code.clone()),
+    }
+}
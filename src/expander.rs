@@ -0,0 +1,1158 @@
+use crate::{
+    environment::Env,
+    error::SchemeError,
+    evaluator::evaluate,
+    types::{
+        default_env::{append, is_eq, list},
+        function::{Builtin, BuiltinFn, Control, Function},
+        macros::{
+            alias, join_list, new_mark, original_name, split_list, strip_aliases, unalias, Macro,
+            SyntaxRules,
+        },
+        symbol::Symbol,
+        Exp, SpecialForm, Value,
+    },
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/*
+    Expansion - rewrites every macro use in an expression before it is
+    evaluated. The expander tracks the names bound by enclosing forms, so that
+    local variables shadow macros and identifiers introduced by a macro (which
+    are renamed to aliases) resolve where the macro was defined.
+
+    Free identifiers introduced by a macro refer to what they meant where the
+    macro was defined. A local variable at the use site that would capture one
+    is renamed, and the expression expanded again.
+*/
+
+/// How deeply lists can nest in an expression. Expanding and analyzing recurse
+/// into each list, so deeper expressions would overflow the native stack.
+/// Quoted data is copied without recursing, so it may nest to any depth.
+const MAX_NESTING: usize = 256;
+
+/// Expands the macro uses in an expression. Macro transformers written as
+/// procedures run with the given maximum depth.
+pub(crate) fn expand(
+    exp: &Exp,
+    env: &Rc<RefCell<Env>>,
+    max_depth: usize,
+) -> Result<Exp, SchemeError> {
+    check_nesting(exp)?;
+    let mut renamed = HashSet::new();
+    loop {
+        let mut expander = Expander::new(env, max_depth);
+        expander.renamed = renamed;
+        let expanded = expander.expand(exp)?;
+        if expander.captured.is_empty() {
+            return Ok(expanded);
+        }
+        renamed = expander.renamed;
+        renamed.extend(expander.captured);
+    }
+}
+
+/// Expands an expression once if it is a macro use, leaving its subforms
+/// untouched. Returns None if it isn't a macro use.
+pub(crate) fn expand_once(
+    exp: &Exp,
+    env: &Rc<RefCell<Env>>,
+    max_depth: usize,
+) -> Result<Option<Exp>, SchemeError> {
+    Expander::new(env, max_depth).expand_once(exp)
+}
+
+#[derive(Clone, PartialEq)]
+enum Binding {
+    // The nth local variable bound while expanding, or None for a global
+    Variable(Option<usize>),
+    // The scope depth the macro was defined at, which its aliases resolve in
+    Macro(Macro, usize),
+}
+
+struct Expander {
+    env: Rc<RefCell<Env>>,
+    scope: Vec<HashMap<Symbol, Binding>>,
+    marks: HashMap<usize, usize>,
+    // The name expanded code refers to each local variable by
+    bound: Vec<Symbol>,
+    // Local variables that captured an identifier introduced by a macro, and
+    // those renamed so they can't
+    captured: HashSet<usize>,
+    renamed: HashSet<usize>,
+    // For the machines running macro transformers
+    max_depth: usize,
+}
+
+impl Expander {
+    fn new(env: &Rc<RefCell<Env>>, max_depth: usize) -> Self {
+        Self {
+            env: env.clone(),
+            scope: Vec::new(),
+            marks: HashMap::new(),
+            bound: Vec::new(),
+            captured: HashSet::new(),
+            renamed: HashSet::new(),
+            max_depth,
+        }
+    }
+
+    fn expand(&mut self, exp: &Exp) -> Result<Exp, SchemeError> {
+        match exp {
+            Exp::Atom(Value::Symbol(name)) => match self.resolve(*name, self.scope.len()) {
+                (Binding::Variable(variable), name) => {
+                    self.check_capture(variable, name);
+                    Ok(Exp::Atom(Value::Symbol(name)))
+                }
+                (Binding::Macro(..), name) => Err(SchemeError::new(format!(
+                    "Invalid use of syntax keyword {}",
+                    name
+                ))),
+            },
+            Exp::Atom(Value::Quote(quoted)) => Ok(quote(strip_aliases(quoted))),
+            Exp::Atom(Value::Quasiquote(template)) => self.quasiquote(template, 1),
+            Exp::Atom(Value::Unquote(_) | Value::UnquoteSplicing(_)) => Err(SchemeError::new(
+                format!("{} used outside of a quasiquote", exp),
+            )),
+            Exp::Atom(_) | Exp::Nil => Ok(exp.clone()),
+            Exp::Pair(_) if !exp.is_list() => Ok(exp.clone()),
+            Exp::Pair(_) => match self.expand_head(exp)? {
+                exp @ Exp::Pair(_) if exp.is_list() => {
+                    let list = exp.unwrap_list()?;
+                    match list.first() {
+                        Some(Exp::Atom(Value::SpecialForm(form))) => {
+                            self.expand_special_form(form, &list)
+                        }
+                        _ => self.expand_each(&list),
+                    }
+                }
+                exp => self.expand(&exp),
+            },
+        }
+    }
+
+    /// Rewrites a quasiquote template nested `depth` levels deep into an
+    /// expression that builds it, expanding the parts unquoted at level 1 so
+    /// they are evaluated. The builtins used are inserted as values rather
+    /// than names so they can't be shadowed.
+    fn quasiquote(&mut self, template: &Exp, depth: usize) -> Result<Exp, SchemeError> {
+        if !contains_unquote(template) {
+            return Ok(quote(strip_aliases(template)));
+        }
+        match template {
+            Exp::Atom(Value::Unquote(exp)) if depth == 1 => self.expand(exp),
+            Exp::Atom(Value::UnquoteSplicing(_)) if depth == 1 => Err(SchemeError::new(format!(
+                "{} used outside of a list",
+                template
+            ))),
+            Exp::Atom(value) => {
+                let (depth, wrapper) = match value {
+                    Value::Quasiquote(_) => (depth + 1, builtin("quasiquote", &wrap_quasiquote)),
+                    Value::Unquote(_) => (depth - 1, builtin("unquote", &wrap_unquote)),
+                    Value::UnquoteSplicing(_) => (
+                        depth - 1,
+                        builtin("unquote-splicing", &wrap_unquote_splicing),
+                    ),
+                    _ => (depth, builtin("quote", &wrap_quote)),
+                };
+                let inner = self.quasiquote(value.quoted().unwrap(), depth)?;
+                Ok(Exp::list(vec![wrapper, inner]))
+            }
+            Exp::Pair(_) | Exp::Nil => {
+                let (items, tail) = split_list(template).unwrap();
+                let mut segments = vec![builtin("append", &append)];
+                for item in &items {
+                    match item {
+                        Exp::Atom(Value::UnquoteSplicing(exp)) if depth == 1 => {
+                            segments.push(self.expand(exp)?)
+                        }
+                        _ => segments.push(Exp::list(vec![
+                            builtin("list", &list),
+                            self.quasiquote(item, depth)?,
+                        ])),
+                    }
+                }
+                if let Some(tail) = tail {
+                    segments.push(self.quasiquote(&tail, depth)?);
+                }
+                Ok(Exp::list(segments))
+            }
+        }
+    }
+
+    fn expand_each(&mut self, list: &[Exp]) -> Result<Exp, SchemeError> {
+        Ok(Exp::list(
+            list.iter()
+                .map(|exp| self.expand(exp))
+                .collect::<Result<Vec<Exp>, SchemeError>>()?,
+        ))
+    }
+
+    /// Transforms the expression until it is no longer a macro use.
+    fn expand_head(&mut self, exp: &Exp) -> Result<Exp, SchemeError> {
+        let mut exp = exp.clone();
+        while let Some(expanded) = self.expand_once(&exp)? {
+            exp = expanded;
+        }
+        Ok(exp)
+    }
+
+    fn expand_once(&mut self, exp: &Exp) -> Result<Option<Exp>, SchemeError> {
+        let name = match exp.split_first() {
+            Some((Exp::Atom(Value::Symbol(name)), _)) if exp.is_list() => name,
+            _ => return Ok(None),
+        };
+        match self.resolve(name, self.scope.len()) {
+            (Binding::Macro(transformer, depth), _) => {
+                let mark = new_mark();
+                self.marks.insert(mark, depth);
+                // A literal matches an identifier bound to the same thing
+                let use_depth = self.scope.len();
+                let same_binding = |literal: Symbol, input: Symbol| {
+                    self.resolve(literal, depth) == self.resolve(input, use_depth)
+                };
+                let expanded =
+                    transformer.transform(exp, mark, &same_binding, &self.env, self.max_depth)?;
+                Ok(Some(expanded))
+            }
+            (Binding::Variable(_), _) => Ok(None),
+        }
+    }
+
+    /// Finds what a name refers to, looking through the innermost `depth`
+    /// scopes and then the runtime environment. Returns the binding and the
+    /// name the expanded code should use for it.
+    fn resolve(&self, name: Symbol, depth: usize) -> (Binding, Symbol) {
+        for frame in self.scope[..depth].iter().rev() {
+            match frame.get(&name) {
+                Some(Binding::Variable(Some(variable))) => {
+                    return (Binding::Variable(Some(*variable)), self.bound[*variable])
+                }
+                Some(binding) => return (binding.clone(), name),
+                None => (),
+            }
+        }
+        if let Some((base, mark)) = unalias(name) {
+            let depth = self.marks.get(&mark).copied().unwrap_or(0).min(depth);
+            return self.resolve(base, depth);
+        }
+        if let Ok(Exp::Atom(Value::Macro(transformer))) = self.env.borrow().get(name) {
+            return (Binding::Macro(transformer, 0), name);
+        }
+        (Binding::Variable(None), name)
+    }
+
+    /// Notes the local variable the expanded code will find by this name, if
+    /// it isn't the one the name was resolved to. Only an identifier
+    /// introduced by a macro can be captured this way, by a variable bound
+    /// between where the macro was defined and where it is used.
+    fn check_capture(&mut self, variable: Option<usize>, name: Symbol) {
+        let found = self
+            .scope
+            .iter()
+            .rev()
+            .find_map(|frame| match frame.get(&name) {
+                Some(Binding::Variable(Some(found))) if self.bound[*found] == name => Some(*found),
+                _ => None,
+            });
+        if let Some(found) = found.filter(|found| Some(*found) != variable) {
+            self.captured.insert(found);
+        }
+    }
+
+    /// Adds a variable to the innermost scope, returning the name to bind it
+    /// to. Top level definitions always use the original name, and variables
+    /// that captured an identifier introduced by a macro get a new one.
+    fn bind(&mut self, name: Symbol) -> Symbol {
+        if self.scope.is_empty() {
+            return original_name(name);
+        }
+        let variable = self.bound.len();
+        self.bound.push(match self.renamed.contains(&variable) {
+            true => alias(original_name(name), new_mark()),
+            false => name,
+        });
+        let frame = self.scope.last_mut().unwrap();
+        frame.insert(name, Binding::Variable(Some(variable)));
+        self.bound[variable]
+    }
+
+    fn expand_special_form(
+        &mut self,
+        form: &SpecialForm,
+        list: &[Exp],
+    ) -> Result<Exp, SchemeError> {
+        match form {
+            SpecialForm::Define => self.expand_define(list),
+            SpecialForm::Lambda if list.len() > 1 => {
+                self.scope.push(HashMap::new());
+                let params = self.bind_params(&list[1]);
+                let body = self.expand_body(&list[2..]);
+                self.scope.pop();
+                Ok(Exp::list([vec![list[0].clone(), params], body?].concat()))
+            }
+            SpecialForm::LambdaStar if list.len() > 1 => {
+                self.scope.push(HashMap::new());
+                let params = self.bind_params_star(&list[1]);
+                let body = params.and_then(|params| Ok((params, self.expand_body(&list[2..])?)));
+                self.scope.pop();
+                let (params, body) = body?;
+                Ok(Exp::list([vec![list[0].clone(), params], body].concat()))
+            }
+            SpecialForm::DefineStar => self.expand(&expand_define_star(list)?),
+            SpecialForm::DefineRecordType => Ok(self.expand_define_record_type(list)),
+            SpecialForm::CaseLambda => {
+                let mut result = vec![list[0].clone()];
+                for clause in &list[1..] {
+                    result.push(match clause {
+                        Exp::Pair(_) => {
+                            let lambda = Exp::cons(special(SpecialForm::Lambda), clause.clone());
+                            self.expand(&lambda)?.cdr()?
+                        }
+                        _ => clause.clone(),
+                    });
+                }
+                Ok(Exp::list(result))
+            }
+            SpecialForm::Let if matches!(list.get(1), Some(Exp::Atom(Value::Symbol(_)))) => {
+                self.expand(&expand_named_let(list)?)
+            }
+            SpecialForm::LetStar => self.expand(&expand_let_star(list)?),
+            SpecialForm::Letrec | SpecialForm::LetrecStar => {
+                self.expand(&expand_letrec(form, list)?)
+            }
+            SpecialForm::Let if list.len() > 1 => {
+                self.scope.push(HashMap::new());
+                let bindings = self.expand_let_bindings(&list[1]);
+                let body = self.expand_body(&list[2..]);
+                self.scope.pop();
+                Ok(Exp::list(
+                    [vec![list[0].clone(), bindings?], body?].concat(),
+                ))
+            }
+            SpecialForm::DefineSyntax => {
+                self.define_syntax(list)?;
+                Ok(quote(Exp::new_list()))
+            }
+            SpecialForm::DefineMacro => {
+                self.define_macro(list)?;
+                Ok(quote(Exp::new_list()))
+            }
+            SpecialForm::LetSyntax | SpecialForm::LetrecSyntax => {
+                self.expand_let_syntax(form, list)
+            }
+            SpecialForm::SyntaxRules => Err(SchemeError::new(
+                "syntax-rules is only valid as a macro transformer".to_string(),
+            )),
+            SpecialForm::Cond => self.expand(&cond_clauses(&list[1..], quote(Exp::new_list()))?),
+            SpecialForm::Case => self.expand(&expand_case(list)?),
+            SpecialForm::When | SpecialForm::Unless => self.expand(&expand_when(form, list)?),
+            SpecialForm::Guard => self.expand(&expand_guard(list)?),
+            SpecialForm::LetValues | SpecialForm::LetStarValues => {
+                self.expand(&expand_let_values(form, list)?)
+            }
+            SpecialForm::DefineValues => self.expand(&expand_define_values(list)?),
+            SpecialForm::Receive => self.expand(&expand_receive(list)?),
+            _ => Ok(Exp::list(
+                [
+                    vec![list[0].clone()],
+                    self.expand_each(&list[1..])?.unwrap_list()?,
+                ]
+                .concat(),
+            )),
+        }
+    }
+
+    fn expand_define(&mut self, list: &[Exp]) -> Result<Exp, SchemeError> {
+        match list.get(1) {
+            Some(Exp::Atom(Value::Symbol(name))) => {
+                let name = self.bind(*name);
+                let values = self.expand_each(&list[2..])?.unwrap_list()?;
+                Ok(Exp::list(
+                    [
+                        vec![list[0].clone(), Exp::Atom(Value::Symbol(name))],
+                        values,
+                    ]
+                    .concat(),
+                ))
+            }
+            Some(signature @ Exp::Pair(_)) => {
+                let (items, tail) = split_list(signature).unwrap();
+                let name = match items.first() {
+                    Some(Exp::Atom(Value::Symbol(name))) => self.bind(*name),
+                    _ => return Ok(Exp::list(list.to_vec())),
+                };
+                self.scope.push(HashMap::new());
+                let mut params = vec![Exp::Atom(Value::Symbol(name))];
+                params.extend(items[1..].iter().map(|param| self.bind_params(param)));
+                let tail = tail.map(|tail| self.bind_params(&tail));
+                let body = self.expand_body(&list[2..]);
+                self.scope.pop();
+
+                Ok(Exp::list(
+                    [vec![list[0].clone(), join_list(params, tail)], body?].concat(),
+                ))
+            }
+            _ => Ok(Exp::list(list.to_vec())),
+        }
+    }
+
+    /// Binds the names defined by a define-record-type. Field names are left
+    /// alone since they only label the record's slots.
+    fn expand_define_record_type(&mut self, list: &[Exp]) -> Exp {
+        let mut result = list[..1].to_vec();
+        for (i, exp) in list.iter().enumerate().skip(1) {
+            result.push(match (i, exp.split_first()) {
+                (2, Some((name, fields))) => Exp::cons(self.bind_params(&name), fields),
+                (1..=3, _) => self.bind_params(exp),
+                (_, Some((field, names))) => Exp::cons(field, self.bind_params(&names)),
+                _ => exp.clone(),
+            });
+        }
+        Exp::list(result)
+    }
+
+    /// Binds every symbol in a parameter list, which may be a list, an
+    /// improper list or a single symbol.
+    fn bind_params(&mut self, params: &Exp) -> Exp {
+        match params {
+            Exp::Atom(Value::Symbol(name)) => Exp::Atom(Value::Symbol(self.bind(*name))),
+            Exp::Pair(_) => {
+                let (items, tail) = split_list(params).unwrap();
+                let items = items.iter().map(|p| self.bind_params(p)).collect();
+                join_list(items, tail.map(|tail| self.bind_params(&tail)))
+            }
+            Exp::Atom(_) | Exp::Nil => params.clone(),
+        }
+    }
+
+    /// Binds the parameters of a lambda*, expanding each default once the
+    /// parameters before it are bound, since it is evaluated in their scope.
+    fn bind_params_star(&mut self, params: &Exp) -> Result<Exp, SchemeError> {
+        let bind = |expander: &mut Self, param: &Exp| match param.unwrap_list().as_deref() {
+            Ok([name, default]) => {
+                let name = expander.bind_params(name);
+                Ok(Exp::list(vec![name, expander.expand(default)?]))
+            }
+            _ => Ok(expander.bind_params(param)),
+        };
+        match split_list(params) {
+            Some((items, tail)) => {
+                let items = items
+                    .iter()
+                    .map(|param| bind(self, param))
+                    .collect::<Result<Vec<Exp>, SchemeError>>()?;
+                Ok(join_list(items, tail.map(|tail| self.bind_params(&tail))))
+            }
+            None => Ok(self.bind_params(params)),
+        }
+    }
+
+    fn expand_let_bindings(&mut self, bindings: &Exp) -> Result<Exp, SchemeError> {
+        let bindings = match bindings.unwrap_list() {
+            Ok(bindings) => bindings,
+            _ => return Ok(bindings.clone()),
+        };
+        let mut result = Vec::new();
+        for binding in bindings {
+            match binding.unwrap_list() {
+                Ok(pair) if !pair.is_empty() => {
+                    let values = self.expand_each(&pair[1..])?.unwrap_list()?;
+                    let name = self.bind_params(&pair[0]);
+                    result.push(Exp::list([vec![name], values].concat()));
+                }
+                _ => result.push(binding.clone()),
+            }
+        }
+        Ok(Exp::list(result))
+    }
+
+    /// Expands the body of a lambda or let, where define-syntax adds a macro
+    /// to the body's own scope.
+    fn expand_body(&mut self, body: &[Exp]) -> Result<Vec<Exp>, SchemeError> {
+        let mut result = Vec::new();
+        for exp in body {
+            let exp = self.expand_head(exp)?;
+            match exp.split_first() {
+                Some((Exp::Atom(Value::SpecialForm(SpecialForm::DefineSyntax)), _)) => {
+                    self.define_syntax(&exp.unwrap_list()?)?
+                }
+                Some((Exp::Atom(Value::SpecialForm(SpecialForm::DefineMacro)), _)) => {
+                    self.define_macro(&exp.unwrap_list()?)?
+                }
+                _ => result.push(self.expand(&exp)?),
+            }
+        }
+        if result.is_empty() && !body.is_empty() {
+            result.push(quote(Exp::new_list()));
+        }
+        Ok(result)
+    }
+
+    fn define_syntax(&mut self, list: &[Exp]) -> Result<(), SchemeError> {
+        match list {
+            [_, Exp::Atom(Value::Symbol(name)), spec] => {
+                let transformer = self.make_macro(spec)?;
+                self.bind_macro(*name, transformer);
+                Ok(())
+            }
+            _ => Err(SchemeError::new(format!(
+                "define-syntax expects a name and a transformer, found {}",
+                Exp::list(list.to_vec())
+            ))),
+        }
+    }
+
+    /// Handles (define-macro (name params...) body...) and
+    /// (define-macro name transformer), evaluating the transformer right away.
+    fn define_macro(&mut self, list: &[Exp]) -> Result<(), SchemeError> {
+        let (name, transformer) = match list {
+            [_, signature @ Exp::Pair(_), body @ ..] if !body.is_empty() => {
+                let (items, tail) = split_list(signature).unwrap();
+                match items.first() {
+                    Some(Exp::Atom(Value::Symbol(name))) => {
+                        let params = join_list(items[1..].to_vec(), tail);
+                        let lambda = [
+                            vec![Exp::Atom(Value::SpecialForm(SpecialForm::Lambda)), params],
+                            body.to_vec(),
+                        ]
+                        .concat();
+                        (*name, Exp::list(lambda))
+                    }
+                    _ => return Err(define_macro_error(list)),
+                }
+            }
+            [_, Exp::Atom(Value::Symbol(name)), transformer] => (*name, transformer.clone()),
+            _ => return Err(define_macro_error(list)),
+        };
+
+        let transformer = self.expand(&transformer)?;
+        match evaluate(&transformer, &mut self.env.clone(), self.max_depth)? {
+            Exp::Atom(Value::Function(Function::Lambda(lambda))) => {
+                self.bind_macro(name, Macro::Lambda(lambda));
+                Ok(())
+            }
+            transformer => Err(SchemeError::new(format!(
+                "define-macro expects a lambda as the transformer, found {}",
+                transformer
+            ))),
+        }
+    }
+
+    /// Binds a macro in the innermost scope, or in the environment at the top
+    /// level.
+    fn bind_macro(&mut self, name: Symbol, transformer: Macro) {
+        let depth = self.scope.len();
+        match self.scope.last_mut() {
+            Some(frame) => {
+                frame.insert(name, Binding::Macro(transformer, depth));
+            }
+            None => self
+                .env
+                .borrow_mut()
+                .set(original_name(name), &Exp::Atom(Value::Macro(transformer))),
+        }
+    }
+
+    fn expand_let_syntax(&mut self, form: &SpecialForm, list: &[Exp]) -> Result<Exp, SchemeError> {
+        let bindings = match list.get(1).map(Exp::unwrap_list) {
+            Some(Ok(bindings)) => bindings,
+            _ => {
+                return Err(SchemeError::new(format!(
+                    "{} expects a list of bindings",
+                    form
+                )))
+            }
+        };
+        // Transformers bound by letrec-syntax can refer to each other
+        let depth = match form {
+            SpecialForm::LetrecSyntax => self.scope.len() + 1,
+            _ => self.scope.len(),
+        };
+        let mut frame = HashMap::new();
+        for binding in &bindings {
+            if let Ok([Exp::Atom(Value::Symbol(name)), spec]) = binding.unwrap_list().as_deref() {
+                let transformer = self.make_macro(spec)?;
+                frame.insert(*name, Binding::Macro(transformer, depth));
+                continue;
+            }
+            return Err(SchemeError::new(format!(
+                "{} expects (name transformer) bindings, found {}",
+                form, binding
+            )));
+        }
+
+        self.scope.push(frame);
+        let body = self.expand_body(&list[2..]);
+        self.scope.pop();
+        Ok(Exp::list(
+            [
+                vec![
+                    Exp::Atom(Value::SpecialForm(SpecialForm::Let)),
+                    Exp::new_list(),
+                ],
+                body?,
+            ]
+            .concat(),
+        ))
+    }
+
+    fn make_macro(&self, spec: &Exp) -> Result<Macro, SchemeError> {
+        match spec.split_first() {
+            Some((Exp::Atom(Value::SpecialForm(SpecialForm::SyntaxRules)), rules)) => Ok(
+                Macro::SyntaxRules(SyntaxRules::from(&rules.unwrap_list()?)?),
+            ),
+            _ => Err(SchemeError::new(format!(
+                "Expected a syntax-rules transformer, found {}",
+                spec
+            ))),
+        }
+    }
+}
+
+/*
+    Derived forms - rewritten into core forms, using special forms and
+    primitives inserted as values and temporaries named with aliases so
+    nothing in the rewritten code can be shadowed or captured
+*/
+
+/// Rewrites (guard (var clause...) body...) into a with-exception-handler call
+/// whose handler escapes to the guard's continuation to evaluate the clauses,
+/// returning to the handler to re-raise the object if none of them match.
+fn expand_guard(list: &[Exp]) -> Result<Exp, SchemeError> {
+    let spec = match list.get(1).map(Exp::unwrap_list) {
+        Some(Ok(spec)) => spec,
+        _ => Vec::new(),
+    };
+    let (var, clauses) = match spec.split_first() {
+        Some((var @ Exp::Atom(Value::Symbol(_)), clauses)) if list.len() > 2 => {
+            (var.clone(), clauses)
+        }
+        _ => {
+            return Err(SchemeError::new(format!(
+                "guard expects (variable clauses...) and a body, found {}",
+                Exp::list(list.to_vec())
+            )))
+        }
+    };
+    let guard_k = temporary("guard-k");
+    let handler_k = temporary("handler-k");
+    let condition = temporary("condition");
+    let result = temporary("result");
+
+    let reraise = Exp::list(vec![
+        handler_k.clone(),
+        lambda(
+            vec![],
+            vec![Exp::list(vec![
+                control(Control::RaiseContinuable),
+                condition.clone(),
+            ])],
+        ),
+    ]);
+    let clauses = let_form(
+        vec![(var, condition.clone())],
+        vec![cond_clauses(clauses, reraise)?],
+    );
+    let handler = lambda(
+        vec![condition],
+        vec![Exp::list(vec![Exp::list(vec![
+            control(Control::CallCC),
+            lambda(
+                vec![handler_k],
+                vec![Exp::list(vec![
+                    guard_k.clone(),
+                    lambda(vec![], vec![clauses]),
+                ])],
+            ),
+        ])])],
+    );
+    let body = lambda(
+        vec![],
+        vec![let_form(
+            vec![(result.clone(), let_form(vec![], list[2..].to_vec()))],
+            vec![Exp::list(vec![
+                guard_k.clone(),
+                lambda(vec![], vec![result]),
+            ])],
+        )],
+    );
+    Ok(Exp::list(vec![Exp::list(vec![
+        control(Control::CallCC),
+        lambda(
+            vec![guard_k],
+            vec![Exp::list(vec![
+                control(Control::WithExceptionHandler),
+                handler,
+                body,
+            ])],
+        ),
+    ])]))
+}
+
+/// The (name value) pairs bound by a let-like form, checking it has a body.
+fn let_bindings(
+    form: &SpecialForm,
+    bindings: Option<&Exp>,
+    body: &[Exp],
+) -> Result<Vec<(Exp, Exp)>, SchemeError> {
+    let error = || SchemeError::new(format!("{} expects a list of bindings and a body", form));
+    let bindings = match bindings.map(Exp::unwrap_list) {
+        Some(Ok(bindings)) if !body.is_empty() => bindings,
+        _ => return Err(error()),
+    };
+    bindings
+        .iter()
+        .map(|binding| match binding.unwrap_list().as_deref() {
+            Ok([name, value]) => Ok((name.clone(), value.clone())),
+            _ => Err(SchemeError::new(format!(
+                "{} expects (name value) bindings, found {}",
+                form, binding
+            ))),
+        })
+        .collect()
+}
+
+/// Rewrites (let* (binding...) body...) into nested lets, one per binding.
+fn expand_let_star(list: &[Exp]) -> Result<Exp, SchemeError> {
+    let body = list.get(2..).unwrap_or_default();
+    let bindings = let_bindings(&SpecialForm::LetStar, list.get(1), body)?;
+    let mut bindings = bindings.into_iter().rev();
+    let mut exp = let_form(bindings.next().into_iter().collect(), body.to_vec());
+    for binding in bindings {
+        exp = let_form(vec![binding], vec![exp]);
+    }
+    Ok(exp)
+}
+
+/// Rewrites letrec and letrec* into a let binding every name, which are then
+/// assigned their values in the new scope. letrec evaluates all the values
+/// before assigning any of them, letrec* assigns each in turn.
+fn expand_letrec(form: &SpecialForm, list: &[Exp]) -> Result<Exp, SchemeError> {
+    let body = list.get(2..).unwrap_or_default();
+    let bindings = let_bindings(form, list.get(1), body)?;
+    let unassigned = bindings
+        .iter()
+        .map(|(name, _)| (name.clone(), quote(Exp::new_list())))
+        .collect();
+    let set =
+        |name: &Exp, value: Exp| Exp::list(vec![special(SpecialForm::Set), name.clone(), value]);
+    let mut exps = Vec::new();
+    match form {
+        SpecialForm::Letrec if !bindings.is_empty() => {
+            // Named after their bindings, which name the procedures they hold
+            let temporaries: Vec<Exp> = bindings
+                .iter()
+                .map(|(name, _)| temporary(&name.to_string()))
+                .collect();
+            let assignments = bindings
+                .iter()
+                .zip(&temporaries)
+                .map(|((name, _), temporary)| set(name, temporary.clone()))
+                .collect();
+            let values = temporaries
+                .into_iter()
+                .zip(bindings.into_iter().map(|(_, value)| value))
+                .collect();
+            exps.push(let_form(values, assignments));
+        }
+        _ => exps.extend(bindings.into_iter().map(|(name, value)| set(&name, value))),
+    }
+    exps.push(let_form(vec![], body.to_vec()));
+    Ok(let_form(unassigned, exps))
+}
+
+/// Rewrites let-values and let*-values into nested calls to call-with-values,
+/// one per binding. let-values binds the values to temporaries first so none
+/// of the names are visible to the other bindings' expressions.
+fn expand_let_values(form: &SpecialForm, list: &[Exp]) -> Result<Exp, SchemeError> {
+    let body = list.get(2..).unwrap_or_default();
+    let bindings = let_bindings(form, list.get(1), body)?;
+    let mut renamed = Vec::new();
+    let mut calls = Vec::new();
+    for (formals, value) in bindings {
+        let formals = match form {
+            SpecialForm::LetValues => {
+                let (names, tail) = split_formals(&formals);
+                let mut rename = |name: Exp| {
+                    let temporary = temporary(&name.to_string());
+                    renamed.push((name, temporary.clone()));
+                    temporary
+                };
+                let names = names.into_iter().map(&mut rename).collect();
+                join_list(names, tail.map(rename))
+            }
+            _ => formals,
+        };
+        calls.push((formals, value));
+    }
+    let mut exp = match form {
+        SpecialForm::LetValues => let_form(renamed, body.to_vec()),
+        _ => let_form(vec![], body.to_vec()),
+    };
+    for (formals, value) in calls.into_iter().rev() {
+        exp = call_with_values(value, formals, vec![exp]);
+    }
+    Ok(exp)
+}
+
+/// Rewrites (define-values formals exp) into definitions of each name, which
+/// are then assigned the values returned by exp.
+fn expand_define_values(list: &[Exp]) -> Result<Exp, SchemeError> {
+    let (formals, value) = match list {
+        [_, formals, value] => (formals, value),
+        _ => {
+            return Err(SchemeError::new(
+                "define-values expects formals and an expression".to_string(),
+            ))
+        }
+    };
+    let (names, tail) = split_formals(formals);
+    let names: Vec<Exp> = names.into_iter().chain(tail.clone()).collect();
+    let temporaries: Vec<Exp> = names
+        .iter()
+        .map(|name| temporary(&name.to_string()))
+        .collect();
+    let params = match tail {
+        Some(_) => join_list(
+            temporaries[..temporaries.len() - 1].to_vec(),
+            temporaries.last().cloned(),
+        ),
+        None => Exp::list(temporaries.clone()),
+    };
+    let mut exps = vec![special(SpecialForm::Begin)];
+    exps.extend(names.iter().map(|name| {
+        Exp::list(vec![
+            special(SpecialForm::Define),
+            name.clone(),
+            quote(Exp::new_list()),
+        ])
+    }));
+    let mut assignments: Vec<Exp> = names
+        .into_iter()
+        .zip(temporaries)
+        .map(|(name, temporary)| Exp::list(vec![special(SpecialForm::Set), name, temporary]))
+        .collect();
+    assignments.push(quote(Exp::new_list()));
+    exps.push(call_with_values(value.clone(), params, assignments));
+    Ok(Exp::list(exps))
+}
+
+/// Rewrites (receive formals exp body...) into a call to call-with-values.
+fn expand_receive(list: &[Exp]) -> Result<Exp, SchemeError> {
+    match list {
+        [_, formals, value, body @ ..] if !body.is_empty() => Ok(call_with_values(
+            value.clone(),
+            formals.clone(),
+            body.to_vec(),
+        )),
+        _ => Err(SchemeError::new(
+            "receive expects formals, an expression and a body".to_string(),
+        )),
+    }
+}
+
+/// The names in a list of formals and its rest parameter, if there is one.
+fn split_formals(formals: &Exp) -> (Vec<Exp>, Option<Exp>) {
+    split_list(formals).unwrap_or_else(|| (Vec::new(), Some(formals.clone())))
+}
+
+/// Calls a procedure taking formals with the values returned by exp.
+fn call_with_values(exp: Exp, formals: Exp, body: Vec<Exp>) -> Exp {
+    Exp::list(vec![
+        control(Control::CallWithValues),
+        lambda(vec![], vec![exp]),
+        Exp::list([vec![special(SpecialForm::Lambda), formals], body].concat()),
+    ])
+}
+
+/// Rewrites (let name (binding...) body...) into a call to a procedure bound
+/// by letrec, so the body can loop by calling name.
+fn expand_named_let(list: &[Exp]) -> Result<Exp, SchemeError> {
+    let name = list[1].clone();
+    let body = list.get(3..).unwrap_or_default();
+    let bindings = let_bindings(&SpecialForm::Let, list.get(2), body)?;
+    let (params, values): (Vec<Exp>, Vec<Exp>) = bindings.into_iter().unzip();
+    let procedure = Exp::list(vec![
+        special(SpecialForm::Letrec),
+        Exp::list(vec![Exp::list(vec![
+            name.clone(),
+            lambda(params, body.to_vec()),
+        ])]),
+        name,
+    ]);
+    Ok(Exp::list([vec![procedure], values].concat()))
+}
+
+/// Rewrites (case key clause...) into cond clauses comparing the key to each
+/// clause's data with eq?.
+fn expand_case(list: &[Exp]) -> Result<Exp, SchemeError> {
+    let key = match list.get(1) {
+        Some(key) => key.clone(),
+        None => {
+            return Err(SchemeError::new(
+                "case expects a key and clauses".to_string(),
+            ))
+        }
+    };
+    let value = temporary("key");
+    let mut clauses = Vec::new();
+    for clause in &list[2..] {
+        let items = clause.unwrap_list().unwrap_or_default();
+        let (data, body) = match items.split_first() {
+            Some((data, body)) if !body.is_empty() => (data, body),
+            _ => return Err(SchemeError::new(format!("Invalid clause {}", clause))),
+        };
+        let test = match data {
+            _ if data.is_list() => {
+                let comparisons = data.iter().map(|datum| {
+                    Exp::list(vec![
+                        builtin("eq?", &is_eq),
+                        value.clone(),
+                        quote(datum.clone()),
+                    ])
+                });
+                Exp::list([vec![special(SpecialForm::Or)], comparisons.collect()].concat())
+            }
+            _ if is_symbol(data, "else") => data.clone(),
+            _ => return Err(SchemeError::new(format!("Invalid clause {}", clause))),
+        };
+        // A receiver is passed the key rather than the result of the test
+        let body = match body {
+            [arrow, receiver] if is_symbol(arrow, "=>") => {
+                vec![Exp::list(vec![receiver.clone(), value.clone()])]
+            }
+            _ => body.to_vec(),
+        };
+        clauses.push(Exp::list([vec![test], body].concat()));
+    }
+    Ok(let_form(
+        vec![(value, key)],
+        vec![cond_clauses(&clauses, quote(Exp::new_list()))?],
+    ))
+}
+
+/// Rewrites (when test body...) and (unless test body...) into an if.
+fn expand_when(form: &SpecialForm, list: &[Exp]) -> Result<Exp, SchemeError> {
+    if list.len() < 3 {
+        return Err(SchemeError::new(format!(
+            "{} expects a test and a body",
+            form
+        )));
+    }
+    let body = sequence(&list[2..]);
+    let (consequent, alternative) = match form {
+        SpecialForm::Unless => (quote(Exp::new_list()), body),
+        _ => (body, quote(Exp::new_list())),
+    };
+    Ok(Exp::list(vec![
+        special(SpecialForm::If),
+        list[1].clone(),
+        consequent,
+        alternative,
+    ]))
+}
+
+/// Rewrites cond clauses into nested ifs, evaluating `otherwise` if no clause
+/// matches.
+fn cond_clauses(clauses: &[Exp], otherwise: Exp) -> Result<Exp, SchemeError> {
+    let (clause, rest) = match clauses.split_first() {
+        Some(split) => split,
+        None => return Ok(otherwise),
+    };
+    let items = clause.unwrap_list().unwrap_or_default();
+    let (test, body) = match items.split_first() {
+        Some((test, body)) => (test.clone(), body),
+        _ => return Err(SchemeError::new(format!("Invalid clause {}", clause))),
+    };
+    if is_symbol(&test, "else") {
+        return match (rest.is_empty(), body.is_empty()) {
+            (true, false) => Ok(sequence(body)),
+            (false, _) => Err(SchemeError::new(format!(
+                "else must be the last clause, found {}",
+                clause
+            ))),
+            (_, true) => Err(SchemeError::new(format!("Invalid clause {}", clause))),
+        };
+    }
+    let otherwise = cond_clauses(rest, otherwise)?;
+    match body {
+        [] => Ok(Exp::list(vec![special(SpecialForm::Or), test, otherwise])),
+        [arrow, receiver] if is_symbol(arrow, "=>") => {
+            let value = temporary("value");
+            Ok(let_form(
+                vec![(value.clone(), test)],
+                vec![Exp::list(vec![
+                    special(SpecialForm::If),
+                    value.clone(),
+                    Exp::list(vec![receiver.clone(), value]),
+                    otherwise,
+                ])],
+            ))
+        }
+        _ => Ok(Exp::list(vec![
+            special(SpecialForm::If),
+            test,
+            sequence(body),
+            otherwise,
+        ])),
+    }
+}
+
+/// (define* (name . params) body...) becomes (define name (lambda* params body...)).
+fn expand_define_star(list: &[Exp]) -> Result<Exp, SchemeError> {
+    if let Some(signature @ Exp::Pair(_)) = list.get(1) {
+        let (items, tail) = split_list(signature).unwrap();
+        if let Some((name @ Exp::Atom(Value::Symbol(_)), params)) = items.split_first() {
+            let params = join_list(params.to_vec(), tail);
+            return Ok(Exp::list(vec![
+                special(SpecialForm::Define),
+                name.clone(),
+                Exp::list(
+                    [
+                        vec![special(SpecialForm::LambdaStar), params],
+                        list[2..].to_vec(),
+                    ]
+                    .concat(),
+                ),
+            ]));
+        }
+    }
+    Err(SchemeError::new(format!(
+        "define* expects a signature and a body, found {}",
+        Exp::list(list.to_vec())
+    )))
+}
+
+/// Raises an error if an expression nests too deeply to expand. Walks it with
+/// a stack of its own, so the check itself can't overflow. Quoted data, and
+/// quasiquote templates without unquotes, aren't expanded so aren't counted.
+fn check_nesting(exp: &Exp) -> Result<(), SchemeError> {
+    let mut pending = vec![(exp.clone(), 0)];
+    while let Some((exp, depth)) = pending.pop() {
+        if depth > MAX_NESTING {
+            return Err(SchemeError::new(format!(
+                "Maximum recursion depth exceeded in an expression nested over {} deep",
+                MAX_NESTING
+            )));
+        }
+        match exp {
+            Exp::Atom(Value::Quote(_)) => (),
+            Exp::Atom(value) => {
+                if let Some(quoted) = value.quoted().filter(|quoted| contains_unquote(quoted)) {
+                    pending.push((quoted.clone(), depth + 1));
+                }
+            }
+            // Other pairs are left as they are, like the expander does
+            Exp::Pair(_) if exp.is_list() => {
+                pending.extend(exp.iter().map(|item| (item, depth + 1)))
+            }
+            Exp::Pair(_) | Exp::Nil => (),
+        }
+    }
+    Ok(())
+}
+
+fn is_symbol(exp: &Exp, name: &str) -> bool {
+    matches!(exp, Exp::Atom(Value::Symbol(symbol)) if original_name(*symbol) == Symbol::new(name))
+}
+
+/// A fresh alias of a name, which only renames the original name so aliases
+/// of aliases aren't made for every use of a derived form.
+fn temporary(name: &str) -> Exp {
+    Exp::Atom(Value::Symbol(alias(
+        original_name(Symbol::new(name)),
+        new_mark(),
+    )))
+}
+
+fn special(form: SpecialForm) -> Exp {
+    Exp::Atom(Value::SpecialForm(form))
+}
+
+fn control(control: Control) -> Exp {
+    Exp::Atom(Value::Function(Function::Control(control)))
+}
+
+/// A single expression, or a begin evaluating several in order.
+fn sequence(body: &[Exp]) -> Exp {
+    match body {
+        [exp] => exp.clone(),
+        _ => Exp::list([vec![special(SpecialForm::Begin)], body.to_vec()].concat()),
+    }
+}
+
+fn lambda(params: Vec<Exp>, body: Vec<Exp>) -> Exp {
+    Exp::list([vec![special(SpecialForm::Lambda), Exp::list(params)], body].concat())
+}
+
+fn let_form(bindings: Vec<(Exp, Exp)>, body: Vec<Exp>) -> Exp {
+    let bindings = bindings
+        .into_iter()
+        .map(|(name, value)| Exp::list(vec![name, value]))
+        .collect();
+    Exp::list([vec![special(SpecialForm::Let), Exp::list(bindings)], body].concat())
+}
+
+fn define_macro_error(list: &[Exp]) -> SchemeError {
+    SchemeError::new(format!(
+        "define-macro expects a signature and a body, found {}",
+        Exp::list(list.to_vec())
+    ))
+}
+
+fn contains_unquote(exp: &Exp) -> bool {
+    let mut pending = vec![exp.clone()];
+    while let Some(exp) = pending.pop() {
+        match exp {
+            Exp::Pair(_) => {
+                let mut iter = exp.iter();
+                pending.extend(iter.by_ref());
+                pending.push(iter.rest);
+            }
+            Exp::Nil => (),
+            Exp::Atom(Value::Unquote(_) | Value::UnquoteSplicing(_)) => return true,
+            Exp::Atom(value) => pending.extend(value.quoted().cloned()),
+        }
+    }
+    false
+}
+
+fn builtin(name: &str, func: &'static BuiltinFn) -> Exp {
+    Exp::Atom(Value::Function(Function::Builtin(Builtin {
+        func,
+        name: name.to_string(),
+    })))
+}
+
+fn wrap_quote(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    Ok(Exp::Atom(Value::Quote(Box::new(
+        args.unwrap_list()?[0].clone(),
+    ))))
+}
+
+fn wrap_quasiquote(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    Ok(Exp::Atom(Value::Quasiquote(Box::new(
+        args.unwrap_list()?[0].clone(),
+    ))))
+}
+
+fn wrap_unquote(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    Ok(Exp::Atom(Value::Unquote(Box::new(
+        args.unwrap_list()?[0].clone(),
+    ))))
+}
+
+fn wrap_unquote_splicing(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    Ok(Exp::Atom(Value::UnquoteSplicing(Box::new(
+        args.unwrap_list()?[0].clone(),
+    ))))
+}
+
+fn quote(exp: Exp) -> Exp {
+    Exp::Atom(Value::Quote(Box::new(exp)))
+}
@@ -0,0 +1,426 @@
+use crate::{
+    environment::{Env, Globals},
+    types::{
+        function::{Function, Lambda},
+        macros::Macro,
+        promise::PromiseState,
+        symbol, Exp, Pair, Value,
+    },
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+/*
+    Garbage collection - values are reference counted, which frees everything
+    except cycles, and cycles are easy to make: a procedure defined in an
+    environment closes over the environment holding it. Environments, global
+    tables and pairs, the objects cycles go through, are tracked as they are
+    made, and the collector finds the ones kept alive only by each other.
+
+    Collecting counts the references each tracked object gets from the others.
+    An object with more references than that is referenced from outside the
+    heap, by a reader or the running machine, and everything reachable from it
+    is live. The rest are only referenced by each other, so their contents are
+    cleared, breaking the cycles and letting reference counting free them.
+
+    Values that aren't tracked, such as promises, records and continuations,
+    are looked inside when the object being traced holds their only reference.
+    Otherwise they count as references from outside the heap, so whatever they
+    hold is kept, which is always safe.
+
+    Freeing a frame, promise or record sets aside what it held on a list
+    rather than freeing it recursively, and the first one freed frees the
+    list in a loop, so no length of chain through them overflows the stack.
+*/
+
+/// Tracked objects allocated between automatic collections, at least.
+const MIN_ALLOCATIONS: usize = 100_000;
+
+#[derive(Default)]
+struct Heap {
+    envs: Slots<RefCell<Env>>,
+    globals: Vec<Weak<Globals>>,
+    pairs: Slots<Pair>,
+    // Objects tracked since the last collection
+    allocated: usize,
+    // Objects found live by the last collection
+    survivors: usize,
+    collections: usize,
+    freed: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+    static GARBAGE: RefCell<Garbage> = RefCell::new(Garbage::default());
+}
+
+/// What the values being freed held, waiting to be freed in turn.
+#[derive(Default)]
+struct Garbage {
+    pending: Vec<Freed>,
+    // Whether a value further up the stack is freeing the pending ones
+    freeing: bool,
+}
+
+/// Something held by a value being freed.
+pub(crate) enum Freed {
+    Exp(Exp),
+    Env(Rc<RefCell<Env>>),
+    Promise(PromiseState),
+}
+
+/// The state of the heap, as reported by heap-stats.
+pub(crate) struct HeapStats {
+    pub(crate) environments: usize,
+    pub(crate) pairs: usize,
+    pub(crate) collections: usize,
+    pub(crate) freed: usize,
+    // Names in the symbol table, which are never freed
+    pub(crate) symbols: usize,
+}
+
+/// Tracks an environment being made, returning the slot it should give up
+/// when freed.
+pub(crate) fn track_env(env: &Weak<RefCell<Env>>) -> usize {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated += 1;
+        heap.envs.insert(env)
+    })
+}
+
+pub(crate) fn untrack_env(slot: usize) {
+    // The heap is gone if the thread is exiting
+    let _ = HEAP.try_with(|heap| heap.borrow_mut().envs.remove(slot));
+}
+
+pub(crate) fn track_globals(globals: &Rc<Globals>) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.globals.push(Rc::downgrade(globals));
+        heap.allocated += 1;
+    })
+}
+
+/// Tracks a pair being made, returning the slot it should give up when freed.
+pub(crate) fn track_pair(pair: &Weak<Pair>) -> usize {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated += 1;
+        heap.pairs.insert(pair)
+    })
+}
+
+pub(crate) fn untrack_pair(slot: usize) {
+    let _ = HEAP.try_with(|heap| heap.borrow_mut().pairs.remove(slot));
+}
+
+/// Frees what a value being freed held, along with anything freed in turn,
+/// in a loop rather than recursively.
+pub(crate) fn free(freed: impl IntoIterator<Item = Freed>) {
+    // Freed recursively if the thread is exiting
+    let Ok(freeing) = GARBAGE.try_with(|garbage| {
+        let mut garbage = garbage.borrow_mut();
+        garbage.pending.extend(freed);
+        std::mem::replace(&mut garbage.freeing, true)
+    }) else {
+        return;
+    };
+    if freeing {
+        return;
+    }
+    // Popped before dropping, since dropping may add more
+    while let Some(next) = GARBAGE.with(|garbage| garbage.borrow_mut().pending.pop()) {
+        match next {
+            Freed::Exp(exp) => drop(exp),
+            Freed::Env(env) => drop(env),
+            Freed::Promise(state) => drop(state),
+        }
+    }
+    GARBAGE.with(|garbage| garbage.borrow_mut().freeing = false);
+}
+
+pub(crate) fn stats() -> HeapStats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let globals = heap.globals.iter().filter(|g| g.strong_count() > 0);
+        HeapStats {
+            environments: heap.envs.len() + globals.count(),
+            pairs: heap.pairs.len(),
+            collections: heap.collections,
+            freed: heap.freed,
+            symbols: symbol::count(),
+        }
+    })
+}
+
+/// Collects once enough has been allocated since the last collection,
+/// growing with the heap so collecting takes amortized constant time.
+pub(crate) fn collect_if_due() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated >= MIN_ALLOCATIONS.max(heap.survivors)
+    });
+    if due {
+        collect();
+    }
+}
+
+/// Frees the tracked objects only referenced by each other, returning how
+/// many there were.
+pub(crate) fn collect() -> usize {
+    let objects = HEAP.with(|heap| heap.borrow_mut().objects());
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (object.address(), i))
+        .collect();
+
+    let mut internal = vec![0; objects.len()];
+    let mut roots = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        let traced = object.trace(&mut |address| {
+            if let Some(&j) = index.get(&address) {
+                internal[j] += 1;
+            }
+        });
+        // Objects in use can't be looked inside, so are kept along with
+        // everything they might hold
+        if !traced {
+            roots.push(i);
+        }
+    }
+    // Not counting the reference held here
+    roots.extend((0..objects.len()).filter(|&i| objects[i].strong_count() - 1 > internal[i]));
+
+    let mut live = vec![false; objects.len()];
+    for &i in &roots {
+        live[i] = true;
+    }
+    while let Some(i) = roots.pop() {
+        objects[i].trace(&mut |address| {
+            if let Some(&j) = index.get(&address) {
+                if !live[j] {
+                    live[j] = true;
+                    roots.push(j);
+                }
+            }
+        });
+    }
+
+    let mut freed = 0;
+    for (object, _) in objects.iter().zip(&live).filter(|(_, live)| !**live) {
+        object.clear();
+        freed += 1;
+    }
+    let survivors = objects.len() - freed;
+    // Dropping the last references frees the garbage
+    drop(objects);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        heap.allocated = 0;
+        heap.survivors = survivors;
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+    freed
+}
+
+impl Heap {
+    /// Every tracked object still alive.
+    fn objects(&mut self) -> Vec<Object> {
+        self.prune();
+        let envs = self.envs.objects.iter().filter_map(Weak::upgrade);
+        let globals = self.globals.iter().filter_map(Weak::upgrade);
+        let pairs = self.pairs.objects.iter().filter_map(Weak::upgrade);
+        envs.map(Object::Env)
+            .chain(globals.map(Object::Globals))
+            .chain(pairs.map(Object::Pair))
+            .collect()
+    }
+
+    /// Forgets global tables that have been freed.
+    fn prune(&mut self) {
+        self.globals.retain(|globals| globals.strong_count() > 0);
+    }
+}
+
+/// Objects tracked in slots they give up when freed, so their memory is
+/// released at once rather than held by the heap until it is pruned.
+struct Slots<T> {
+    objects: Vec<Weak<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    fn insert(&mut self, object: &Weak<T>) -> usize {
+        match self.free.pop() {
+            Some(slot) => {
+                self.objects[slot] = object.clone();
+                slot
+            }
+            None => {
+                self.objects.push(object.clone());
+                self.objects.len() - 1
+            }
+        }
+    }
+
+    fn remove(&mut self, slot: usize) {
+        self.objects[slot] = Weak::new();
+        self.free.push(slot);
+    }
+
+    /// The number of objects alive.
+    fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+}
+
+enum Object {
+    Env(Rc<RefCell<Env>>),
+    Globals(Rc<Globals>),
+    Pair(Rc<Pair>),
+}
+
+impl Object {
+    fn address(&self) -> usize {
+        match self {
+            Object::Env(env) => address(env),
+            Object::Globals(globals) => address(globals),
+            Object::Pair(pair) => address(pair),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Env(env) => Rc::strong_count(env),
+            Object::Globals(globals) => Rc::strong_count(globals),
+            Object::Pair(pair) => Rc::strong_count(pair),
+        }
+    }
+
+    /// Calls visit with the address of each object this one references,
+    /// returning false if it is in use so couldn't be fully traced.
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let mut tracer = Tracer {
+            visit,
+            complete: true,
+        };
+        match self {
+            Object::Env(env) => tracer.borrowed(env, |tracer, env| env.trace(tracer)),
+            Object::Globals(globals) => tracer.borrowed(globals, |tracer, globals| {
+                globals.values().for_each(|value| tracer.exp(value))
+            }),
+            Object::Pair(pair) => pair.trace(&mut tracer),
+        }
+        tracer.complete
+    }
+
+    fn clear(&self) {
+        match self {
+            Object::Env(env) => {
+                let contents = env.borrow_mut().clear();
+                drop(contents);
+            }
+            Object::Globals(globals) => {
+                let contents = std::mem::take(&mut *globals.borrow_mut());
+                drop(contents);
+            }
+            Object::Pair(pair) => drop(pair.clear()),
+        }
+    }
+}
+
+fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+/// Finds the tracked objects referenced by a value.
+pub(crate) struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(usize),
+    complete: bool,
+}
+
+impl Tracer<'_> {
+    /// A reference to a tracked object.
+    pub(crate) fn object<T: ?Sized>(&mut self, object: &Rc<T>) {
+        (self.visit)(address(object))
+    }
+
+    pub(crate) fn exp(&mut self, exp: &Exp) {
+        match exp {
+            Exp::Pair(pair) => self.object(pair),
+            Exp::Nil => {}
+            Exp::Atom(value) => self.value(value),
+        }
+    }
+
+    pub(crate) fn lambda(&mut self, lambda: &Lambda) {
+        self.object(&lambda.env)
+    }
+
+    /// Traces a value held in a RefCell, unless it is being changed.
+    pub(crate) fn borrowed<T>(&mut self, cell: &RefCell<T>, trace: impl FnOnce(&mut Self, &T)) {
+        match cell.try_borrow() {
+            Ok(value) => trace(self, &value),
+            Err(_) => self.complete = false,
+        }
+    }
+
+    /// Traces an untracked shared value if this is its only reference.
+    pub(crate) fn owned<T: ?Sized>(&mut self, value: &Rc<T>, trace: impl FnOnce(&mut Self, &T)) {
+        if Rc::strong_count(value) == 1 {
+            trace(self, value)
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Quote(exp)
+            | Value::Quasiquote(exp)
+            | Value::Unquote(exp)
+            | Value::UnquoteSplicing(exp) => self.exp(exp),
+            Value::Values(values) => values.iter().for_each(|value| self.exp(value)),
+            Value::Condition(condition) => condition.irritants.iter().for_each(|exp| self.exp(exp)),
+            Value::Function(Function::Lambda(lambda)) => self.lambda(lambda),
+            Value::Function(Function::CaseLambda(clauses)) => {
+                clauses.iter().for_each(|lambda| self.lambda(lambda))
+            }
+            Value::Promise(promise) => promise.trace(self),
+            Value::Record(record) => self.owned(&record.values, |tracer, values| {
+                tracer.borrowed(values, |tracer, values| {
+                    values.iter().for_each(|value| tracer.exp(value))
+                })
+            }),
+            Value::Continuation(continuation) => continuation.trace(self),
+            Value::Macro(Macro::Lambda(lambda)) => self.lambda(lambda),
+            Value::Macro(Macro::SyntaxRules(rules)) => rules.rules.iter().for_each(|(a, b)| {
+                self.exp(a);
+                self.exp(b);
+            }),
+            Value::Boolean(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Symbol(_)
+            | Value::Keyword(_)
+            | Value::SpecialForm(_)
+            | Value::Function(Function::Builtin(_) | Function::Control(_) | Function::Record(_))
+            | Value::RecordType(_)
+            | Value::Eof => {}
+        }
+    }
+}
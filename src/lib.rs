@@ -1,7 +1,11 @@
+mod analyzer;
 mod buffer;
+mod compiler;
 mod environment;
 mod error;
 mod evaluator;
+mod expander;
+mod gc;
 mod parser;
 pub mod reader;
 mod tokenizer;
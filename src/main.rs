@@ -1,4 +1,4 @@
-use lisp_rs::reader::Reader;
+use lisp_rs::reader::{Backend, Reader};
 use pico_args;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
@@ -25,30 +25,54 @@ struct Config {
     filename: Option<String>,
     interactive: bool,
     verbose: bool,
+    backend: Backend,
+    max_depth: Option<usize>,
 }
 
 impl Config {
-    fn new(filename: Option<String>, interactive: bool, verbose: bool) -> Self {
+    fn new(
+        filename: Option<String>,
+        interactive: bool,
+        verbose: bool,
+        backend: Backend,
+        max_depth: Option<usize>,
+    ) -> Self {
         Config {
             filename,
             interactive,
             verbose,
+            backend,
+            max_depth,
         }
     }
 }
 
 fn config_from_args(mut args: pico_args::Arguments) -> Result<Config, pico_args::Error> {
+    // Flags are taken out first so they aren't read as the filename
+    let backend = match args.contains("--vm") {
+        true => Backend::Vm,
+        false => Backend::Evaluator,
+    };
+    let max_depth = args.opt_value_from_str("--max-depth")?;
     let filename = args.opt_free_from_str()?;
     let interactive = filename.is_none() || args.contains("-i");
     let verbose = args.contains("-v");
 
-    Ok(Config::new(filename, verbose, interactive))
+    Ok(Config::new(
+        filename,
+        verbose,
+        interactive,
+        backend,
+        max_depth,
+    ))
 }
 
 fn read_eval_print(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut reader = Reader::new();
+    let mut reader = Reader::with_backend(config.backend);
+    if let Some(max_depth) = config.max_depth {
+        reader.set_max_depth(max_depth);
+    }
 
-    read_from_file(String::from("std.scm"), &mut reader)?;
     if let Some(f) = config.filename {
         read_from_file(f, &mut reader)?;
     }
@@ -87,6 +111,8 @@ fn read_eval_print(config: Config) -> Result<(), Box<dyn Error>> {
         }
 
         match reader.eval() {
+            // Multiple values are printed one per line, and no values print nothing
+            Ok(result) if result.is_empty() => {}
             Ok(result) => println!("{}", result),
             Err(error) => println!("Runtime error: {:?}", error),
         }
@@ -31,10 +31,9 @@ pub(crate) fn parse(buffer: &mut Buffer) -> Result<Exp, SchemeError> {
 
             let last = buffer.pop_front().unwrap();
             match last {
-                Token::EndExp => Ok(Exp::from(&exp[..])),
+                Token::EndExp => Ok(Exp::list(exp)),
                 Token::Dot => {
                     let cdr = parse(buffer)?;
-                    exp.push(cdr);
                     if buffer
                         .pop_front()
                         .ok_or(SchemeError::new("Unexpected EOF while parsing".to_string()))?
@@ -42,7 +41,7 @@ pub(crate) fn parse(buffer: &mut Buffer) -> Result<Exp, SchemeError> {
                     {
                         return Err(SchemeError::new("'.' missing ')'".to_string()));
                     }
-                    Ok(Exp::imp_from(&exp))
+                    Ok(Exp::improper_list(exp, cdr))
                 }
                 _ => panic!("Fatal error while parsing: List terminator was: {:?}", last),
             }
@@ -50,6 +49,9 @@ pub(crate) fn parse(buffer: &mut Buffer) -> Result<Exp, SchemeError> {
         Token::EndExp => Err(SchemeError::new("Unmatched ')'".to_string())),
         Token::Dot => Err(SchemeError::new("Unbound pair".to_string())),
         Token::Quote => Ok(Exp::Atom(Value::Quote(Box::from(parse(buffer)?)))),
+        Token::Quasiquote => Ok(Exp::Atom(Value::Quasiquote(Box::from(parse(buffer)?)))),
+        Token::Unquote => Ok(Exp::Atom(Value::Unquote(Box::from(parse(buffer)?)))),
+        Token::UnquoteSplicing => Ok(Exp::Atom(Value::UnquoteSplicing(Box::from(parse(buffer)?)))),
         Token::Literal(value) => Ok(Exp::Atom(value.clone())),
     }
 }
@@ -2,27 +2,60 @@ use wasm_bindgen::prelude::*;
 
 use crate::buffer::Buffer;
 use crate::environment::Env;
-use crate::evaluator::eval_all;
+use crate::error::SchemeError;
+pub use crate::evaluator::Backend;
+use crate::evaluator::{eval_all, DEFAULT_MAX_DEPTH};
 use crate::parser::parse_all;
 use crate::tokenizer::tokenize;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+/// The standard library written in Scheme, loaded into every reader.
+const PRELUDE: &str = include_str!("../std.scm");
+
+pub(crate) fn load_prelude(
+    env: &mut Rc<RefCell<Env>>,
+    backend: Backend,
+) -> Result<(), SchemeError> {
+    let mut buffer = Buffer::new();
+    tokenize(PRELUDE, &mut buffer)?;
+    eval_all(&parse_all(&mut buffer)?, env, backend, DEFAULT_MAX_DEPTH)?;
+    Ok(())
+}
+
 #[wasm_bindgen]
 pub struct Reader {
     buffer: Buffer,
     env: Rc<RefCell<Env>>,
+    backend: Backend,
+    max_depth: usize,
 }
 
 #[wasm_bindgen]
 impl Reader {
     pub fn new() -> Self {
+        Self::with_backend(Backend::default())
+    }
+
+    /// A reader running expressions with the given backend, which the
+    /// prelude is loaded with too.
+    pub fn with_backend(backend: Backend) -> Self {
+        let mut env = Env::new();
+        load_prelude(&mut env, backend).expect("The prelude should load without errors");
         Reader {
             buffer: Buffer::new(),
-            env: Rc::new(RefCell::new(Env::new())),
+            env,
+            backend,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
+    /// Sets how deep the stack of pending computations can grow before
+    /// calling a procedure raises a maximum recursion depth error.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     pub fn push(&mut self, input: String) -> Result<(), String> {
         if let Err(e) = tokenize(&input, &mut self.buffer) {
             return Err(e.to_string());
@@ -41,7 +74,8 @@ impl Reader {
     pub fn eval(&mut self) -> Result<String, String> {
         let expression = parse_all(&mut self.buffer).map_err(|e| e.to_string())?;
 
-        let result = eval_all(&expression, &mut self.env).map_err(|e| e.to_string())?;
+        let result = eval_all(&expression, &mut self.env, self.backend, self.max_depth)
+            .map_err(|e| e.to_string())?;
 
         Ok(format!("{}", result))
     }
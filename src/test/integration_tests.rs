@@ -0,0 +1,2095 @@
+use super::BACKEND;
+use crate::buffer::Buffer;
+use crate::evaluator::{eval_all, DEFAULT_MAX_DEPTH};
+use crate::parser::parse_all;
+use crate::reader::load_prelude;
+use crate::tokenizer::tokenize;
+use crate::types::function::{Builtin, Function};
+use crate::types::rational::Rational;
+use crate::types::symbol::Symbol;
+use crate::types::Exp;
+use crate::{error::SchemeError, types::Value};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::environment::Env;
+
+fn evaluate_input(input: &str) -> Result<Exp, SchemeError> {
+    let mut env = Env::new();
+    let mut buffer = Buffer::from(input);
+    let exp = parse_all(&mut buffer)?;
+    eval_all(&exp, &mut env, BACKEND, DEFAULT_MAX_DEPTH)
+}
+
+fn evaluate_input_with_env(
+    input: &str,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Exp, SchemeError> {
+    let mut buffer = Buffer::from(input);
+    let exp = parse_all(&mut buffer)?;
+    eval_all(&exp, env, BACKEND, DEFAULT_MAX_DEPTH)
+}
+
+thread_local! {
+    static LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A builtin that records its argument, so tests can observe the order
+/// side effects happen in.
+fn log(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let entry = args.unwrap_list()?[0].to_string();
+    LOG.with(|log| log.borrow_mut().push(entry));
+    Ok(Exp::new_list())
+}
+
+fn env_with_log() -> Rc<RefCell<Env>> {
+    LOG.with(|log| log.borrow_mut().clear());
+    let env = Env::new();
+    let builtin = Builtin {
+        func: &log,
+        name: "log".to_string(),
+    };
+    env.borrow_mut().set(
+        Symbol::new("log"),
+        &Exp::Atom(Value::Function(Function::Builtin(builtin))),
+    );
+    env
+}
+
+fn env_with_prelude() -> Rc<RefCell<Env>> {
+    let mut env = Env::new();
+    load_prelude(&mut env, BACKEND).unwrap();
+    env
+}
+
+fn logged() -> String {
+    LOG.with(|log| log.borrow().join(" "))
+}
+
+#[test]
+fn test_define() {
+    let input = "(define a 1) (define b 2) (define c (+ a b)) c";
+    let result = evaluate_input(&input).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+}
+
+#[test]
+fn test_let() {
+    let result = evaluate_input("(let ((a 1)) a)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("(let ((a 1) (b 2)) (+ a b))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(let ((a 1)) (let ((a 2) (b a)) b))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("(let ((a 1) (b 2) (c (+ a b))) c)").unwrap_err();
+    assert_eq!(result.to_string(), "Name a not found");
+}
+
+#[test]
+fn test_let_star() {
+    let result = evaluate_input("(let* ((a 1) (b 2) (c (+ a b))) c)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(let ((a 1)) (let* ((a 2) (b a)) b))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(let* () 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_letrec() {
+    let result = evaluate_input(
+        "(letrec ((even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+                  (odd? (lambda (n) (if (= n 0) false (even? (- n 1))))))
+           (list (even? 100) (odd? 7) (even? 7)))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true true false)");
+
+    let result = evaluate_input("(letrec* ((a 1) (b (+ a 1))) (list a b))").unwrap();
+    assert_eq!(result.to_string(), "(1 2)");
+
+    let result = evaluate_input("(letrec ((a 1) (b (lambda () a))) (b))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_named_let() {
+    let result = evaluate_input(
+        "(let loop ((i 0) (acc '()))
+           (if (= i 3) acc (loop (+ i 1) (cons i acc))))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(2 1 0)");
+
+    let result =
+        evaluate_input("(let loop ((n 10000)) (if (= n 0) 'done (loop (- n 1))))").unwrap();
+    assert_eq!(result.to_string(), "done");
+
+    let result = evaluate_input("(define loop 5) (let loop ((n loop)) n)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(5.0))));
+}
+
+#[test]
+fn test_lambda_fn() {
+    let result = evaluate_input("((lambda () 1))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("((lambda (a b) (+ a b)) 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+}
+
+#[test]
+fn test_define_fn() {
+    let result = evaluate_input("(define a (lambda () 1)) (a)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("(define (a) 1) (a)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_closures() {
+    let mut env = Env::new();
+    evaluate_input_with_env("(define a 1)", &mut env).unwrap();
+
+    let result = evaluate_input_with_env("((lambda (a) a) 2)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+    let result = evaluate_input_with_env("a", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input_with_env("(let ((a 2)) a)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+    let result = evaluate_input_with_env("a", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_comparisons() {
+    let result = evaluate_input("(= 1 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+    let result = evaluate_input("(= 1 0)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+
+    let result = evaluate_input("(> 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+    let result = evaluate_input("(> 1 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+
+    let result = evaluate_input("(>= 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+    let result = evaluate_input("(>= 1 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+
+    let result = evaluate_input("(<= 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+    let result = evaluate_input("(<= 1 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+}
+
+#[test]
+fn test_if() {
+    let result = evaluate_input("(if true 0 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(0.0))));
+    let result = evaluate_input("(if false 0 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("(if (= 1 1) 0 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(0.0))));
+    let result = evaluate_input("(if (= 1 0) 0 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_and() {
+    let result = evaluate_input("(and 1 2 false true)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+    let result = evaluate_input("(and true 1 2 3)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+}
+
+#[test]
+fn test_or() {
+    let result = evaluate_input("(or 1 2 false true)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+    let result = evaluate_input("(and false false false false)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+}
+
+#[test]
+fn test_eval_apply() {
+    let result = evaluate_input("(eval '(+ 1 1))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+    let result = evaluate_input("(apply + '(1 1))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+}
+
+#[test]
+fn test_list_construction() {
+    let result = evaluate_input("(list)").unwrap();
+    assert_eq!(result, Exp::new_list());
+    let result = evaluate_input("(list 1 2 3)").unwrap();
+    assert_eq!(
+        result,
+        Exp::list(vec![
+            Exp::Atom(Value::Number(Rational::from(1.0))),
+            Exp::Atom(Value::Number(Rational::from(2.0))),
+            Exp::Atom(Value::Number(Rational::from(3.0)))
+        ])
+    );
+
+    let result = evaluate_input("(cons 1 '(2 3))").unwrap();
+    assert_eq!(
+        result,
+        Exp::list(vec![
+            Exp::Atom(Value::Number(Rational::from(1.0))),
+            Exp::Atom(Value::Number(Rational::from(2.0))),
+            Exp::Atom(Value::Number(Rational::from(3.0)))
+        ])
+    );
+}
+
+#[test]
+fn test_list_access() {
+    let result = evaluate_input("(car '(1 2 3))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+    let result = evaluate_input("(car '())").unwrap_err();
+    assert_eq!(result.to_string(), "car called on empty list".to_string());
+
+    let result = evaluate_input("(cdr '(1 2 3))").unwrap();
+    assert_eq!(
+        result,
+        Exp::list(vec![
+            Exp::Atom(Value::Number(Rational::from(2.0))),
+            Exp::Atom(Value::Number(Rational::from(3.0)))
+        ])
+    );
+    let result = evaluate_input("(cdr '())").unwrap_err();
+    assert_eq!(result.to_string(), "cdr called on empty list".to_string());
+}
+
+#[test]
+fn test_improper_list_construction() {
+    let result = evaluate_input("'(1 . 1)").unwrap();
+    assert_eq!(
+        result,
+        Exp::cons(
+            Exp::Atom(Value::Number(Rational {
+                numerator: 1.0,
+                denominator: 1.0
+            })),
+            Exp::Atom(Value::Number(Rational {
+                numerator: 1.0,
+                denominator: 1.0
+            }))
+        )
+    );
+
+    let result = evaluate_input("'(1 2 . 3)").unwrap();
+    assert_eq!(
+        result,
+        Exp::improper_list(
+            vec![
+                Exp::Atom(Value::Number(Rational {
+                    numerator: 1.0,
+                    denominator: 1.0
+                })),
+                Exp::Atom(Value::Number(Rational {
+                    numerator: 2.0,
+                    denominator: 1.0
+                })),
+            ],
+            Exp::Atom(Value::Number(Rational {
+                numerator: 3.0,
+                denominator: 1.0
+            })),
+        )
+    );
+
+    let result = evaluate_input("(cons 1 1)").unwrap();
+    assert_eq!(
+        result,
+        Exp::cons(
+            Exp::Atom(Value::Number(Rational {
+                numerator: 1.0,
+                denominator: 1.0
+            })),
+            Exp::Atom(Value::Number(Rational {
+                numerator: 1.0,
+                denominator: 1.0
+            }))
+        )
+    );
+
+    let result = evaluate_input("(cons '(1 . 2) 3)").unwrap();
+    assert_eq!(
+        result,
+        Exp::cons(
+            Exp::cons(
+                Exp::Atom(Value::Number(Rational {
+                    numerator: 1.0,
+                    denominator: 1.0
+                })),
+                Exp::Atom(Value::Number(Rational {
+                    numerator: 2.0,
+                    denominator: 1.0
+                }))
+            ),
+            Exp::Atom(Value::Number(Rational {
+                numerator: 3.0,
+                denominator: 1.0
+            })),
+        )
+    );
+}
+
+#[test]
+fn test_improper_list_access() {
+    let result = evaluate_input("(car '(1 . 2))").unwrap();
+    assert_eq!(
+        result,
+        Exp::Atom(Value::Number(Rational {
+            numerator: 1.0,
+            denominator: 1.0
+        }))
+    );
+
+    let result = evaluate_input("(car '(1 2 . 3))").unwrap();
+    assert_eq!(
+        result,
+        Exp::Atom(Value::Number(Rational {
+            numerator: 1.0,
+            denominator: 1.0
+        }))
+    );
+
+    let result = evaluate_input("(cdr '(1 2 . 3))").unwrap();
+    assert_eq!(
+        result,
+        Exp::cons(
+            Exp::Atom(Value::Number(Rational {
+                numerator: 2.0,
+                denominator: 1.0,
+            })),
+            Exp::Atom(Value::Number(Rational {
+                numerator: 3.0,
+                denominator: 1.0,
+            }))
+        )
+    );
+}
+
+#[test]
+fn test_shared_pairs() {
+    let mut env = Env::new();
+    evaluate_input_with_env("(define a (list 1 2 3)) (define b (cons 0 a))", &mut env).unwrap();
+    let a = evaluate_input_with_env("a", &mut env).unwrap();
+    let tail = evaluate_input_with_env("(cdr b)", &mut env).unwrap();
+    assert!(matches!((&a, &tail), (Exp::Pair(x), Exp::Pair(y)) if Rc::ptr_eq(x, y)));
+
+    let result = evaluate_input("(cons 1 (cons 2 3))").unwrap();
+    assert_eq!(result.to_string(), "(1 2 . 3)");
+    let result = evaluate_input("(cdr (cons 1 2))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+    let result = evaluate_input("(car 1)").unwrap_err();
+    assert_eq!(result.to_string(), "car called on non-pair 1");
+    let result = evaluate_input("(+ 1 . 2)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Cannot evaluate an improper list (+ 1 . 2)"
+    );
+}
+
+#[test]
+fn test_long_lists() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define (build n) (let loop ((i 0) (acc '())) (if (= i n) acc (loop (+ i 1) (cons i acc)))))
+         (define (len l) (let loop ((l l) (n 0)) (if (empty? l) n (loop (cdr l) (+ n 1)))))
+         (define a (build 20000))
+         (define b (build 20000))",
+        &mut env,
+    )
+    .unwrap();
+    let result = evaluate_input_with_env("(len a)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(20000.0))));
+    let result = evaluate_input_with_env("(equal? a b)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+    let result = evaluate_input_with_env("(car (append a '(1)))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(19999.0))));
+    // Dropping the last reference frees every pair without recursing
+    evaluate_input_with_env("(set! a '()) (set! b '())", &mut env).unwrap();
+}
+
+#[test]
+fn test_mutable_pairs() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define a (list 1 2 3))
+         (define b (cdr a))
+         (set-car! b 20)
+         (set-cdr! (cdr b) '(4))",
+        &mut env,
+    )
+    .unwrap();
+    let result = evaluate_input_with_env("a", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(1 20 3 4)");
+    let result = evaluate_input_with_env("(list-set! a 3 40) b", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(20 3 40)");
+    let result = evaluate_input_with_env("(list-set! a 4 50)", &mut env).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "list-set! index 4 is out of range for (1 20 3 40)"
+    );
+    let result = evaluate_input("(set-car! '() 1)").unwrap_err();
+    assert_eq!(result.to_string(), "set-car! called on non-pair ()");
+
+    let result = evaluate_input_with_env(
+        "(define c (list 5 6))
+         (define d (append! a '() c))
+         (set-car! c 50)
+         (list (eq? d a) d)",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true (1 20 3 40 50 6))");
+    let result =
+        evaluate_input_with_env("(define r (reverse! d)) (list r (eq? r c))", &mut env)
+            .unwrap();
+    assert_eq!(result.to_string(), "((6 50 40 3 20 1) false)");
+    let result = evaluate_input_with_env("(eq? (cdr r) c)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+    let result =
+        evaluate_input("(list (eq? '(1) '(1)) (equal? '(1) '(1)) (eq? 'a 'a))").unwrap();
+    assert_eq!(result.to_string(), "(false true true)");
+}
+
+#[test]
+fn test_eq_procedures() {
+    let result = evaluate_input(
+        "(define (f) 1)
+         (define (make) (lambda () 1))
+         (define g (make))
+         (define h (case-lambda ((x) x) ((x y) y)))
+         (define p (delay 1))
+         (list (eq? f f) (eq? g g) (eq? g (make)) (eq? f g)
+               (eq? h h) (eq? p p) (eq? p (delay 1)) (eq? car car))",
+    )
+    .unwrap();
+    assert_eq!(
+        result.to_string(),
+        "(true true false false true true false true)"
+    );
+
+    let result = evaluate_input("(call/cc (lambda (k) (list (eq? k k) (eq? k car))))").unwrap();
+    assert_eq!(result.to_string(), "(true false)");
+}
+
+#[test]
+fn test_equal_procedures() {
+    let result = evaluate_input(
+        "(define (f) 1)
+         (define (make) (lambda () 1))
+         (define g (make))
+         (list (equal? f f) (equal? (list f) (list f)) (equal? (list f g) (list f g))
+               (equal? g (make)) (equal? (list f) (list g)))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true true true false false)");
+}
+
+#[test]
+fn test_circular_lists() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define a (list 1 2 3))
+         (set-cdr! (cdr (cdr a)) a)
+         (define b (list 1 2 3 1 2 3))
+         (set-cdr! (cdr (cdr (cdr (cdr (cdr b))))) b)",
+        &mut env,
+    )
+    .unwrap();
+    let result = evaluate_input_with_env("a", &mut env).unwrap();
+    assert_eq!(result.to_string(), "#0=(1 2 3 . #0#)");
+    let result =
+        evaluate_input_with_env("(list (car (cdr (cdr (cdr a)))) (equal? a b))", &mut env)
+            .unwrap();
+    assert_eq!(result.to_string(), "(1 true)");
+    let result = evaluate_input_with_env("(apply + a)", &mut env).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Expected a list, found #0=(1 2 3 . #0#)"
+    );
+
+    let result =
+        evaluate_input("(define x (list 1 2)) (set-car! (cdr x) x) (list x x)").unwrap();
+    assert_eq!(result.to_string(), "(#0=(1 #0#) #0#)");
+}
+
+#[test]
+fn test_garbage_collection() {
+    let mut env = Env::new();
+    // Each call leaves a frame holding a procedure closing over it, and a
+    // circular list
+    evaluate_input_with_env(
+        "(define (make n)
+           (define (loop n) (if (= n 0) 0 (loop (- n 1))))
+           (let ((l (list n n)))
+             (set-cdr! (cdr l) l))
+           loop)
+         (define (churn n) (if (> n 0) (begin (make n) (churn (- n 1)))))
+         (define kept (make 0))
+         (define circle (list 1 2 3))
+         (set-cdr! (cdr (cdr circle)) circle)
+         (define count
+           (let ((n 0))
+             (lambda () (set! n (+ n 1)) n)))
+         (count)",
+        &mut env,
+    )
+    .unwrap();
+    let before: usize = evaluate_input_with_env("(gc)", &mut env)
+        .unwrap()
+        .to_string()
+        .parse()
+        .unwrap();
+    evaluate_input_with_env("(churn 50)", &mut env).unwrap();
+    // 50 frames and 100 pairs
+    let result = evaluate_input_with_env("(gc)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(150.0))));
+    let result = evaluate_input_with_env("(gc)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(0.0))));
+
+    // Anything reachable from the environment is kept
+    let result =
+        evaluate_input_with_env("(list (kept 3) (car (cdr (cdr (cdr circle)))) (count))", &mut env)
+            .unwrap();
+    assert_eq!(result.to_string(), "(0 1 2)");
+
+    let result = evaluate_input_with_env("(heap-stats)", &mut env).unwrap();
+    let stats = result.unwrap_list().unwrap();
+    assert_eq!(stats[2].to_string(), "(collections . 3)");
+    assert_eq!(stats[3].to_string(), format!("(freed . {})", before + 150));
+
+    // A global environment's procedures keep it alive once nothing else does
+    let weak = Rc::downgrade(&env);
+    drop(env);
+    assert!(weak.upgrade().is_some());
+    crate::gc::collect();
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_garbage_collection_during_evaluation() {
+    // A single long loop collects as it goes, so the frames it leaves in
+    // cycles don't pile up until it finishes
+    let result = evaluate_input(
+        "(define (make n)
+           (define (loop n) (if (= n 0) 0 (loop (- n 1))))
+           loop)
+         (let loop ((i 0))
+           (if (< i 40000)
+               (begin (make i) (loop (+ i 1)))
+               (let ((stats (heap-stats)))
+                 (list (< (cdr (car stats)) 20000) (> (cdr (car (cdr (cdr stats)))) 0)))))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true true)");
+}
+
+#[test]
+fn test_tail_calls() {
+    let result = evaluate_input(
+        "(define (loop n) (if (= n 0) 0 (loop (- n 1))))
+         (loop 10000)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(0.0))));
+
+    let result = evaluate_input(
+        "(define (even? n) (if (= n 0) true (odd? (- n 1))))
+         (define (odd? n) (if (= n 0) false (even? (- n 1))))
+         (even? 10000)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+
+    let result = evaluate_input(
+        "(define (loop n) (and true (or false (let ((m n)) (if (= m 0) 0 (loop (- m 1)))))))
+         (loop 10000)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(0.0))));
+}
+
+#[test]
+fn test_recursive_frames() {
+    let result = evaluate_input(
+        "(define (sum-to n) (if (= n 0) 0 (+ (sum-to (- n 1)) n)))
+         (sum-to 4)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(10.0))));
+
+    let result = evaluate_input(
+        "(define (count-down n) (if (= n 0) '() (cons n (count-down (- n 1)))))
+         (count-down 3)",
+    )
+    .unwrap();
+    assert_eq!(
+        result,
+        Exp::list(vec![
+            Exp::Atom(Value::Number(Rational::from(3.0))),
+            Exp::Atom(Value::Number(Rational::from(2.0))),
+            Exp::Atom(Value::Number(Rational::from(1.0)))
+        ])
+    );
+}
+
+#[test]
+fn test_returned_closures() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define (make-adder n) (lambda (x) (+ x n)))
+         (define add-one (make-adder 1))
+         (define add-five (make-adder 5))",
+        &mut env,
+    )
+    .unwrap();
+
+    let result = evaluate_input_with_env("(add-one 10)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(11.0))));
+    let result = evaluate_input_with_env("(add-five 10)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(15.0))));
+
+    let result = evaluate_input_with_env("((make-adder 2) (add-one 1))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(4.0))));
+}
+
+#[test]
+fn test_syntax_rules() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define-syntax my-unless
+           (syntax-rules ()
+             ((_ condition body ...) (if condition '() (let () body ...)))))
+         (define-syntax my-assert
+           (syntax-rules ()
+             ((_ e) (if e 'ok (list 'assertion-failed 'e)))))",
+        &mut env,
+    )
+    .unwrap();
+
+    let result = evaluate_input_with_env("(my-unless (= 1 2) 1 2 3)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+    let result = evaluate_input_with_env("(my-unless (= 1 1) 1 2 3)", &mut env).unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input_with_env("(my-assert (= 1 1))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Symbol(Symbol::new("ok"))));
+    let result = evaluate_input_with_env("(my-assert (= 1 2))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(assertion-failed (= 1 2))");
+}
+
+#[test]
+fn test_syntax_rules_ellipsis() {
+    let result = evaluate_input(
+        "(define-syntax my-let*
+           (syntax-rules ()
+             ((_ () body ...) (let () body ...))
+             ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))
+         (my-let* ((a 1) (b (+ a 1))) (* a b))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input(
+        "(define-syntax flatten
+           (syntax-rules ()
+             ((_ (a b ...) ...) '(a ... b ... ...))))
+         (flatten (1 2 3) (4) (5 6))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 4 5 2 3 6)");
+
+    let result = evaluate_input(
+        "(define-syntax tail
+           (syntax-rules ()
+             ((_ a . rest) 'rest)))
+         (tail 1 2 3)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(2 3)");
+
+    let result = evaluate_input(
+        "(define-syntax my-list
+           (syntax-rules ::: ()
+             ((_ x :::) (list x :::))))
+         (my-list 1 2)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 2)");
+}
+
+#[test]
+fn test_syntax_rules_literals() {
+    let result = evaluate_input(
+        "(define-syntax arrow
+           (syntax-rules (=>)
+             ((_ a => b) (list 'forward a b))
+             ((_ a b c) (list 'other a c))))
+         (list (arrow 1 => 2) (arrow 1 2 3))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "((forward 1 2) (other 1 3))");
+
+    let result = evaluate_input(
+        "(define-syntax one-arg (syntax-rules () ((_ a) a)))
+         (one-arg 1 2)",
+    )
+    .unwrap_err();
+    assert_eq!(result.to_string(), "No syntax rule matches (one-arg 1 2)");
+}
+
+#[test]
+fn test_syntax_rules_hygiene() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define-syntax my-or
+           (syntax-rules ()
+             ((_) false)
+             ((_ e) e)
+             ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+         (define t 5)",
+        &mut env,
+    )
+    .unwrap();
+
+    // The macro's t doesn't capture the user's t
+    let result = evaluate_input_with_env("(my-or false t)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(5.0))));
+    let result = evaluate_input_with_env("(let ((t 7)) (my-or false t))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(7.0))));
+
+    // Local variables shadow macros
+    let result =
+        evaluate_input_with_env("((lambda (my-or) (my-or 1)) (lambda (x) x))", &mut env)
+            .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_syntax_rules_hygiene_at_use_site() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define (not x) (if x #f #t))
+         (define-syntax my-unless
+           (syntax-rules () ((_ c b ...) (if (not c) (begin b ...) #f))))
+         (define-syntax inc! (syntax-rules () ((_ v) (set! v (+ v 1)))))
+         (define-syntax arrow? (syntax-rules (=>) ((_ a => b) #t) ((_ a b c) #f)))
+         (define z 1)",
+        &mut env,
+    )
+    .unwrap();
+
+    // Local variables at the use site don't capture the macro's free names
+    let result =
+        evaluate_input_with_env("(let ((not (lambda (x) x))) (my-unless #f 'ran))", &mut env)
+            .unwrap();
+    assert_eq!(result.to_string(), "ran");
+    let result = evaluate_input_with_env("(let ((+ -)) (inc! z) z)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+    let result = evaluate_input_with_env(
+        "(define (f not) (list (my-unless #f (not 3)) (not 4)))
+         (f -)",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(-3 -4)");
+
+    // Nor do they capture names local to where the macro was defined
+    let result = evaluate_input_with_env(
+        "(let ((x 1))
+           (let-syntax ((get-x (syntax-rules () ((_) x))))
+             (let ((x 2)) (list x (get-x)))))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(2 1)");
+
+    // A literal only matches an identifier bound to the same thing
+    let result = evaluate_input_with_env("(arrow? 1 => 2)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+    let result = evaluate_input_with_env("(let ((=> 5)) (arrow? 1 => 2))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(false)));
+}
+
+#[test]
+fn test_let_syntax() {
+    let result = evaluate_input(
+        "(let-syntax ((inc (syntax-rules () ((_ x) (+ x 1)))))
+           (inc 2))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input(
+        "(letrec-syntax ((my-and (syntax-rules ()
+                                   ((_) true)
+                                   ((_ e) e)
+                                   ((_ e r ...) (if e (my-and r ...) false)))))
+           (my-and 1 2 3))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input(
+        "(define (f x)
+           (define-syntax double (syntax-rules () ((_ e) (* 2 e))))
+           (double x))
+         (f 4)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(8.0))));
+
+    let result = evaluate_input(
+        "(let-syntax ((inc (syntax-rules () ((_ x) (+ x 1))))) 1)
+         (inc 2)",
+    )
+    .unwrap_err();
+    assert_eq!(result.to_string(), "Name inc not found");
+}
+
+#[test]
+fn test_define_macro() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define-macro (swap-args f a b) (list f b a))
+         (define-macro my-unless
+           (lambda (condition body) (list 'if condition ''() body)))
+         (define-macro (swap-twice f a b) (list 'swap-args f b a))",
+        &mut env,
+    )
+    .unwrap();
+
+    let result = evaluate_input_with_env("(swap-args - 1 10)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(9.0))));
+    let result = evaluate_input_with_env("(my-unless (= 1 2) 5)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(5.0))));
+    let result = evaluate_input_with_env("(swap-twice - 1 10)", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(-9.0))));
+
+    // Macros aren't hygienic, so they can capture the caller's variables
+    evaluate_input_with_env("(define-macro (get-x) 'x)", &mut env).unwrap();
+    let result = evaluate_input_with_env("(let ((x 3)) (get-x))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+}
+
+#[test]
+fn test_macroexpand() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define-macro (swap-args f a b) (list f b a))
+         (define-macro (swap-twice f a b) (list 'swap-args f b a))
+         (define-syntax my-if
+           (syntax-rules () ((_ c a b) (if c a b))))",
+        &mut env,
+    )
+    .unwrap();
+
+    let result =
+        evaluate_input_with_env("(macroexpand-1 '(swap-twice - 1 10))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(swap-args - 10 1)");
+    let result =
+        evaluate_input_with_env("(macroexpand '(swap-twice - 1 10))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(- 1 10)");
+    let result = evaluate_input_with_env("(macroexpand '(+ 1 2))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(+ 1 2)");
+    let result = evaluate_input_with_env("(macroexpand '(my-if 1 2 3))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(if 1 2 3)");
+
+    let result =
+        evaluate_input_with_env("(eval (macroexpand '(swap-args - 1 10)))", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(9.0))));
+}
+
+#[test]
+fn test_quasiquote() {
+    let result = evaluate_input("`(a b)").unwrap();
+    assert_eq!(result.to_string(), "(a b)");
+
+    let result = evaluate_input("(define b 2) (define rest '(3 4)) `(a ,b ,@rest)").unwrap();
+    assert_eq!(result.to_string(), "(a 2 3 4)");
+
+    let result = evaluate_input("`(1 ,@'() ,(+ 1 1) . ,(+ 1 2))").unwrap();
+    assert_eq!(result.to_string(), "(1 2 . 3)");
+
+    let result = evaluate_input("(let ((list 5)) `(,list ,@'(6)))").unwrap();
+    assert_eq!(result.to_string(), "(5 6)");
+
+    let result = evaluate_input("`,(+ 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input(",a").unwrap_err();
+    assert_eq!(result.to_string(), ",a used outside of a quasiquote");
+}
+
+#[test]
+fn test_nested_quasiquote() {
+    let result = evaluate_input("(define x 5) `(a `(b ,(c ,x)))").unwrap();
+    assert_eq!(result.to_string(), "(a `(b ,(c 5)))");
+
+    let result = evaluate_input("(define x 5) `(a `(b ,,x))").unwrap();
+    assert_eq!(result.to_string(), "(a `(b ,5))");
+
+    let result = evaluate_input("(define x '(1 2)) `(a '(b ,@x))").unwrap();
+    assert_eq!(result.to_string(), "(a '(b 1 2))");
+}
+
+#[test]
+fn test_quasiquote_macros() {
+    let result = evaluate_input(
+        "(define-macro (my-when condition body) `(if ,condition ,body '()))
+         (my-when (= 1 1) 2)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input(
+        "(define-syntax my-list-of
+           (syntax-rules () ((_ x ...) `(items ,x ...))))
+         (define a 1)
+         (my-list-of a a)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(items 1 1)");
+}
+
+#[test]
+fn test_call_cc() {
+    let result = evaluate_input("(+ 1 (call/cc (lambda (k) 1)))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(+ 1 (call/cc (lambda (k) (+ 10 (k 1)))))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result =
+        evaluate_input("(call-with-current-continuation (lambda (k) (k 1) 2))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input(
+        "(define (search wanted lst return)
+           (if (empty? lst)
+               false
+               (if (= (car lst) wanted)
+                   (return lst)
+                   (search wanted (cdr lst) return))))
+         (call/cc (lambda (return) (search 2 '(1 2 3) return) 'not-found))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(2 3)");
+
+    let result = evaluate_input("(function? (call/cc (lambda (k) k)))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+}
+
+#[test]
+fn test_call_cc_reentry() {
+    let result = evaluate_input(
+        "(let ((k (call/cc (lambda (k) k))))
+           (if (number? k) k (k 10)))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(10.0))));
+
+    let mut env = Env::new();
+    evaluate_input_with_env("(define r (list 1 (call/cc (lambda (k) k))))", &mut env).unwrap();
+    let result = evaluate_input_with_env("r", &mut env).unwrap();
+    assert!(result.iter().count() == 2);
+    evaluate_input_with_env("((car (cdr r)) 2)", &mut env).unwrap();
+    let result = evaluate_input_with_env("r", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(1 2)");
+}
+
+#[test]
+fn test_dynamic_wind() {
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env(
+        "(dynamic-wind
+           (lambda () (log 'before))
+           (lambda () (log 'during) 'result)
+           (lambda () (log 'after)))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "result");
+    assert_eq!(logged(), "before during after");
+}
+
+#[test]
+fn test_dynamic_wind_errors() {
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env(
+        "(dynamic-wind
+           (lambda () (log 'outer-before))
+           (lambda ()
+             (dynamic-wind
+               (lambda () (log 'inner-before))
+               (lambda () (car '()))
+               (lambda () (log 'inner-after))))
+           (lambda () (log 'outer-after)))",
+        &mut env,
+    );
+    assert!(result.is_err());
+    assert_eq!(
+        logged(),
+        "outer-before inner-before inner-after outer-after"
+    );
+}
+
+#[test]
+fn test_dynamic_wind_continuations() {
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env(
+        "(call/cc (lambda (k)
+           (dynamic-wind
+             (lambda () (log 'outer-before))
+             (lambda ()
+               (dynamic-wind
+                 (lambda () (log 'inner-before))
+                 (lambda () (k 'escaped) (log 'unreachable))
+                 (lambda () (log 'inner-after))))
+             (lambda () (log 'outer-after)))))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "escaped");
+    assert_eq!(
+        logged(),
+        "outer-before inner-before inner-after outer-after"
+    );
+
+    let mut env = env_with_log();
+    evaluate_input_with_env(
+        "(define k
+           (dynamic-wind
+             (lambda () (log 'in))
+             (lambda () (call/cc (lambda (k) k)))
+             (lambda () (log 'out))))
+         (k 5)",
+        &mut env,
+    )
+    .unwrap();
+    let result = evaluate_input_with_env("k", &mut env).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(5.0))));
+    assert_eq!(logged(), "in out in out");
+}
+
+#[test]
+fn test_strings() {
+    let result = evaluate_input("\"hello (world)\"").unwrap();
+    assert_eq!(
+        result,
+        Exp::Atom(Value::String("hello (world)".to_string()))
+    );
+    assert_eq!(result.to_string(), "\"hello (world)\"");
+
+    let result = evaluate_input("(string? \"a \\\"quoted\\\" word\")").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+
+    let result = tokenize("\"unterminated", &mut Buffer::new()).unwrap_err();
+    assert_eq!(result.to_string(), "Unterminated string literal");
+}
+
+#[test]
+fn test_symbols() {
+    // Symbols with the same name are the same symbol however they were made
+    let result = evaluate_input(
+        "(list (eq? 'abc 'abc)
+               (eq? (string->symbol \"abc\") 'abc)
+               (eq? 'abc 'abd)
+               (symbol? (string->symbol \"has space\")))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true true false true)");
+    assert_eq!(
+        Symbol::new("abc"),
+        Symbol::new(&(String::from("ab") + "c"))
+    );
+
+    let result = evaluate_input("(symbol->string 'hello)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::String("hello".to_string())));
+
+    let result = evaluate_input("(symbol->string (string->symbol \"round trip\"))").unwrap();
+    assert_eq!(result.to_string(), "\"round trip\"");
+
+    let result = evaluate_input("(string->symbol 'abc)").unwrap_err();
+    assert_eq!(result.to_string(), "Expected a string, found abc");
+
+    let result = evaluate_input("(symbol->string \"abc\")").unwrap_err();
+    assert_eq!(result.to_string(), "Expected a symbol, found \"abc\"");
+}
+
+#[test]
+fn test_aliases_are_not_interned() {
+    // The fresh names given by every macro use and derived form don't add to
+    // the symbol table, which is never freed
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define-syntax my-or
+           (syntax-rules () ((_) #f) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+         (define (assq key alist)
+           (if (eq? (car (car alist)) key) (car alist) (assq key (cdr alist))))
+         (define (run n)
+           (if (= n 0)
+               (cdr (assq 'symbols (heap-stats)))
+               (begin
+                 (eval '(guard (e (#t 1)) (my-or #f (let loop ((i 0)) (if (= i 3) i (loop (+ i 1)))))))
+                 (run (- n 1)))))",
+        &mut env,
+    )
+    .unwrap();
+    // The first run also interns the names of the heap stats
+    evaluate_input_with_env("(run 1)", &mut env).unwrap();
+    let before = evaluate_input_with_env("(run 10)", &mut env).unwrap();
+    let after = evaluate_input_with_env("(run 1000)", &mut env).unwrap();
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_raise() {
+    let result = evaluate_input("(raise 'boom)").unwrap_err();
+    assert_eq!(result.to_string(), "Uncaught exception boom");
+
+    let result = evaluate_input("(error \"Something went wrong:\" 1 2)").unwrap_err();
+    assert_eq!(result.to_string(), "Something went wrong: 1 2");
+
+    let result = evaluate_input(
+        "(with-exception-handler
+           (lambda (e) (* e 2))
+           (lambda () (+ 1 (raise-continuable 20))))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(41.0))));
+
+    let result = evaluate_input(
+        "(with-exception-handler
+           (lambda (e) 0)
+           (lambda () (+ 1 (raise 'oops))))",
+    )
+    .unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Exception handler returned from non-continuable raise of oops"
+    );
+
+    let result = evaluate_input(
+        "(call/cc (lambda (k)
+           (with-exception-handler
+             (lambda (e) (k (error-object-message e)))
+             (lambda () (car '())))))",
+    )
+    .unwrap();
+    assert_eq!(
+        result,
+        Exp::Atom(Value::String("car called on empty list".to_string()))
+    );
+}
+
+#[test]
+fn test_guard() {
+    let result = evaluate_input(
+        "(guard (e ((error-object? e)
+                    (list (error-object-message e) (error-object-irritants e))))
+           (error \"bad thing\" 1 2))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(\"bad thing\" (1 2))");
+
+    let result = evaluate_input("(guard (e ((symbol? e) e)) (raise 'oops))").unwrap();
+    assert_eq!(result.to_string(), "oops");
+
+    let result = evaluate_input(
+        "(guard (e ((error-object? e) (error-object-message e))) (+ 1 (car '())))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "\"car called on empty list\"");
+
+    let result =
+        evaluate_input("(guard (e ((and (number? e) e) => (lambda (n) (* n 2)))) (raise 21))")
+            .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(42.0))));
+
+    let result = evaluate_input("(guard (e ((symbol? e)) (else 'other)) (raise 1))").unwrap();
+    assert_eq!(result.to_string(), "other");
+
+    let result = evaluate_input("(guard (e (else 'caught)) 1 2 3)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input(
+        "(guard (e ((symbol? e) (list 'outer e)))
+           (guard (e ((number? e) (list 'inner e)))
+             (raise 'x)))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(outer x)");
+
+    let result = evaluate_input("(guard (e ((number? e) e)) (raise 'x))").unwrap_err();
+    assert_eq!(result.to_string(), "Uncaught exception x");
+}
+
+#[test]
+fn test_guard_dynamic_wind() {
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env(
+        "(guard (e (true (log 'handled) e))
+           (dynamic-wind
+             (lambda () (log 'before))
+             (lambda () (raise 'error))
+             (lambda () (log 'after))))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "error");
+    assert_eq!(logged(), "before after handled");
+}
+
+#[test]
+fn test_recursion_depth() {
+    let mut env = Env::new();
+    let mut evaluate = |input: &str| {
+        let exp = parse_all(&mut Buffer::from(input))?;
+        eval_all(&exp, &mut env, BACKEND, 1000)
+    };
+    evaluate(
+        "(define (count n) (if (= n 0) 0 (+ 1 (count (- n 1)))))
+         (define (try n)
+           (guard (e ((error-object? e) (error-object-message e)))
+             (count n)))",
+    )
+    .unwrap();
+    let result = evaluate("(count 100)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(100.0))));
+    let result = evaluate("(count 10000)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Maximum recursion depth exceeded in count"
+    );
+
+    // Handlers run past the limit, which applies again once they return
+    let result = evaluate("(list (try 10000) (try 100) (try 10000))").unwrap();
+    assert_eq!(
+        result.to_string(),
+        "(\"Maximum recursion depth exceeded in count\" 100 \"Maximum recursion depth exceeded in count\")"
+    );
+
+    let result = evaluate("((lambda (f) (f f)) (lambda (f) (+ 1 (f f))))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Maximum recursion depth exceeded in an anonymous procedure"
+    );
+    let result = evaluate("(let loop ((n 10000)) (if (= n 0) 0 (+ 1 (loop (- n 1)))))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Maximum recursion depth exceeded in loop"
+    );
+
+    // So does running macro transformers
+    let result = evaluate("(define-macro (counted n) (count n)) (counted 100)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(100.0))));
+    let result = evaluate("(counted 10000)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Maximum recursion depth exceeded in count"
+    );
+    let result = evaluate("(define-macro m (begin (count 10000) (lambda () 0)))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Maximum recursion depth exceeded in count"
+    );
+
+    // Tail calls take no stack
+    let result = evaluate("(let loop ((n 10000)) (if (= n 0) 'done (loop (- n 1))))").unwrap();
+    assert_eq!(result.to_string(), "done");
+
+    // Deeply nested lists are printed, compared and freed without overflowing
+    // the Rust stack
+    evaluate(
+        "(define (nest n) (let loop ((n n) (l '())) (if (= n 0) l (loop (- n 1) (list l)))))
+         (define nested (nest 50000))",
+    )
+    .unwrap();
+    let printed = evaluate("nested").unwrap().to_string();
+    assert_eq!(printed.len(), 100_002);
+    assert!(printed.starts_with("((((") && printed.ends_with("))))"));
+    let result = evaluate("(list (equal? nested (nest 50000)) (equal? nested (nest 49999)))").unwrap();
+    assert_eq!(result.to_string(), "(true false)");
+    let result = evaluate("(set! nested #f) 'freed").unwrap();
+    assert_eq!(result.to_string(), "freed");
+
+    // So are long chains of closures and of promises
+    evaluate(
+        "(define (mk n f) (if (= n 0) f (mk (- n 1) (lambda () f))))
+         (define g (mk 100000 (lambda () 0)))
+         (define p (let loop ((n 50000) (p (make-promise 0)))
+                     (if (= n 0) p (loop (- n 1) (make-promise (list p))))))",
+    )
+    .unwrap();
+    let result = evaluate("(set! g 0) (set! p 0) 'freed").unwrap();
+    assert_eq!(result.to_string(), "freed");
+
+    // Code nested too deeply to expand raises the same error
+    let result = evaluate(
+        "(eval (let loop ((n 200) (e 0)) (if (= n 0) e (loop (- n 1) (list '+ 1 e)))))",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(200.0))));
+    let result = evaluate(
+        "(guard (e ((error-object? e) (error-object-message e)))
+           (eval (nest 50000)))",
+    )
+    .unwrap();
+    assert_eq!(
+        result.to_string(),
+        "\"Maximum recursion depth exceeded in an expression nested over 256 deep\""
+    );
+
+    // Quoted data isn't expanded, so may nest deeper
+    let deep = format!("{}{}", "(".repeat(300), ")".repeat(300));
+    let result = evaluate(&format!(
+        "(list (equal? '{deep} (nest 299)) (equal? `{deep} (nest 299)) (equal? `(1 ,@'{deep}) (cons 1 (nest 299))))"
+    ))
+    .unwrap();
+    assert_eq!(result.to_string(), "(true true true)");
+}
+
+#[test]
+fn test_set() {
+    let result = evaluate_input("(define a 1) (set! a (+ a 1)) a").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input(
+        "(define (make-counter)
+           (let ((count 0))
+             (lambda () (set! count (+ count 1)) count)))
+         (define counter (make-counter))
+         (define other (make-counter))
+         (counter)
+         (counter)
+         (other)
+         (list (counter) (other))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(3 2)");
+
+    let result = evaluate_input(
+        "(define total 0)
+         (define (add! n) (set! total (+ total n)))
+         (add! 1)
+         (add! 2)
+         total",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(define a 1) (let ((a 2)) (set! a 3)) a").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input("(set! undefined 1)").unwrap_err();
+    assert_eq!(result.to_string(), "Cannot set! unbound name undefined");
+}
+
+#[test]
+fn test_begin() {
+    let result = evaluate_input("(begin 1 2 3)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(begin (define a 1) (define b 2)) (+ a b)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(if false 1)").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("(if true 1)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+}
+
+#[test]
+fn test_syntax_errors_when_reached() {
+    // Malformed forms are only an error once they are evaluated
+    let env = "(define (f x) (if x 1 (let ((y)) y)))";
+    let result = evaluate_input(&format!("{} (f true)", env)).unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(1.0))));
+
+    let result = evaluate_input(&format!("{} (f false)", env)).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Let expects (name value) definitions, found (y)"
+    );
+
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env("(begin (log 1) (set! 2 3) (log 4))", &mut env);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Expected a symbol as the name, found 2"
+    );
+    assert_eq!(logged(), "1");
+
+    let result =
+        evaluate_input("(guard (e ((error-object? e) (error-object-message e))) (if))").unwrap();
+    assert_eq!(result.to_string(), "\"if expects at least 2 args, found 0\"");
+
+    // Each time a definition runs makes a distinct record type
+    let result = evaluate_input(
+        "(define (make) (define-record-type <t> (mk) t?) (cons mk t?))
+         (define a (make))
+         (define b (make))
+         (list ((cdr a) ((car a))) ((cdr a) ((car b))))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true false)");
+}
+
+#[test]
+fn test_lexical_addressing() {
+    // Variables several frames out, assigned through a closure
+    let result = evaluate_input(
+        "(define (make-counter step)
+           (let ((n 0))
+             (lambda () (let ((by step)) (set! n (+ n by)) n))))
+         (define counter (make-counter 2))
+         (counter)
+         (counter)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(4.0))));
+
+    // Internal definitions can refer to those after them
+    let result = evaluate_input(
+        "(define (parity n)
+           (define (even? n) (if (= n 0) true (odd? (- n 1))))
+           (define (odd? n) (if (= n 0) false (even? (- n 1))))
+           (list (even? n) (odd? n)))
+         (parity 7)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(false true)");
+
+    let result = evaluate_input(
+        "(define x 'global)
+         (define (f x) (list (let ((x (+ x 1))) x) x))
+         (list (f 1) x)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "((2 1) global)");
+
+    // An internal definition hides the outer name even before it runs
+    let result = evaluate_input("(define b 0) (define (f) (define a b) (define b 1) a) (f)");
+    assert_eq!(result.unwrap_err().to_string(), "Name b not found");
+
+    let result = evaluate_input("(define (f) (set! undefined 1)) (f)");
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Cannot set! unbound name undefined"
+    );
+
+    // Code run by eval can define in the frame it runs in
+    let result =
+        evaluate_input("(define (f) (eval '(define z 5)) (eval '(+ z 1))) (f)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(6.0))));
+    // and code analyzed before the definition, which took the name to be
+    // global, finds it there
+    let result = evaluate_input("(define (g x) (eval '(define y (* x 2))) y) (g 4)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(8.0))));
+    let result = evaluate_input("(define (g) (eval '(define y 1)) y) (g) y").unwrap_err();
+    assert_eq!(result.to_string(), "Name y not found");
+}
+
+#[test]
+fn test_cond() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define (sign n)
+           (cond ((< n 0) 'negative)
+                 ((= n 0) 'zero)
+                 (else 'positive)))",
+        &mut env,
+    )
+    .unwrap();
+    let result =
+        evaluate_input_with_env("(list (sign -2) (sign 0) (sign 5))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(negative zero positive)");
+
+    let result = evaluate_input("(cond ((car '(1 2)) => (lambda (x) (+ x 1))))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(cond (false 1) (2))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(cond (false 1))").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("(cond (else 1) (true 2))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "else must be the last clause, found (else 1)"
+    );
+}
+
+#[test]
+fn test_case() {
+    let mut env = Env::new();
+    evaluate_input_with_env(
+        "(define (classify x)
+           (case x
+             ((1 2 3) 'small)
+             ((a b) 'letter)
+             ((10) => (lambda (n) (* n n)))
+             (else 'other)))",
+        &mut env,
+    )
+    .unwrap();
+    let result = evaluate_input_with_env(
+        "(list (classify 2) (classify 'b) (classify 10) (classify 'z))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(small letter 100 other)");
+
+    let result = evaluate_input("(case 5 ((1) 'one) (else => (lambda (n) (+ n 1))))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(6.0))));
+
+    // Data are compared by identity, so a list never matches a fresh copy
+    let result = evaluate_input("(case (list 1) (((1)) 1) (else 2))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+}
+
+#[test]
+fn test_when_unless() {
+    let result = evaluate_input("(define a 0) (when (= a 0) (set! a 1) (+ a 1))").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(when false 1)").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("(unless false 1 2)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result = evaluate_input("(unless true 1)").unwrap();
+    assert_eq!(result, Exp::new_list());
+}
+
+#[test]
+fn test_derived_conditional_tail_calls() {
+    let result = evaluate_input(
+        "(define (count-down n)
+           (cond ((= n 0) 'done)
+                 (else (when true (begin (count-down (- n 1)))))))
+         (count-down 10000)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "done");
+
+    let result = evaluate_input(
+        "(define (loop n)
+           (case n
+             ((0) 'done)
+             (else (unless false (loop (- n 1))))))
+         (loop 10000)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "done");
+}
+
+#[test]
+fn test_rest_params() {
+    let result = evaluate_input("((lambda (a b . rest) (list a b rest)) 1 2 3 4)").unwrap();
+    assert_eq!(result.to_string(), "(1 2 (3 4))");
+
+    let result = evaluate_input("((lambda (a . rest) rest) 1)").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("((lambda args args) 1 2 3)").unwrap();
+    assert_eq!(result.to_string(), "(1 2 3)");
+
+    let result = evaluate_input("(define (f . args) args) (f)").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("(define (f a . args) (cons a args)) (f 1 2 3)").unwrap();
+    assert_eq!(result.to_string(), "(1 2 3)");
+
+    let result = evaluate_input("(define (f a b . rest) a) (f 1)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "(a b . rest) expects at least 2 args, found 1"
+    );
+
+    let result = evaluate_input("(lambda (a . rest) a)").unwrap();
+    assert_eq!(result.to_string(), "(lambda (a . rest) a)");
+
+    let result = evaluate_input("(lambda args args)").unwrap();
+    assert_eq!(result.to_string(), "(lambda args args)");
+
+    let result = evaluate_input(
+        "(define-macro (my-begin . body) `(let () ,@body))
+         (my-begin 1 2 3)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+}
+
+#[test]
+fn test_optional_params() {
+    let result = evaluate_input(
+        "(define* (f a #:optional (b (* a 2)) c) (list a b c))
+         (list (f 1) (f 1 5) (f 1 5 6))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "((1 2 false) (1 5 false) (1 5 6))");
+
+    let result = evaluate_input("(define* (f a #:optional b) a) (f 1 2 3)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "(a #:optional b) takes a maximum of 2 args, found 3"
+    );
+
+    let result =
+        evaluate_input("((lambda* (#:optional (a 1) #:rest r) (list a r)) 2 3 4)").unwrap();
+    assert_eq!(result.to_string(), "(2 (3 4))");
+
+    let result = evaluate_input("(lambda* (a #:optional (b 2)) b)").unwrap();
+    assert_eq!(result.to_string(), "(lambda* (a #:optional (b 2)) b)");
+
+    let result = evaluate_input("(lambda (a #:optional b) a)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Parameter list expects symbols, found #:optional"
+    );
+}
+
+#[test]
+fn test_keyword_params() {
+    let result = evaluate_input(
+        "(define* (make-point #:key (x 0) (y x)) (list x y))
+         (list (make-point) (make-point #:y 3) (make-point #:y 3 #:x 4) (make-point #:x 5))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "((0 0) (0 3) (4 3) (5 5))");
+
+    let result =
+        evaluate_input("(define* (f a #:optional b #:key c) (list a b c)) (f 1 #:c 3)")
+            .unwrap();
+    assert_eq!(result.to_string(), "(1 false 3)");
+
+    let result = evaluate_input("(define* (f #:key x) x) (f #:z 1)").unwrap_err();
+    assert_eq!(result.to_string(), "Unknown keyword argument #:z");
+
+    let result = evaluate_input("(define* (f #:key x) x) (f #:x)").unwrap_err();
+    assert_eq!(result.to_string(), "Keyword argument #:x has no value");
+
+    let result = evaluate_input("(define* (f #:key x) x) (f 1 2)").unwrap_err();
+    assert_eq!(result.to_string(), "Expected a keyword argument, found 1");
+
+    let result = evaluate_input("'#:key").unwrap();
+    assert_eq!(result.to_string(), "#:key");
+}
+
+#[test]
+fn test_case_lambda() {
+    let result = evaluate_input(
+        "(define area
+           (case-lambda
+             ((r) (* 3 r r))
+             ((w h) (* w h))
+             ((w h . more) (cons (* w h) more))))
+         (list (area 2) (area 2 3) (area 2 3 4 5))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(12 6 (6 4 5))");
+
+    let result = evaluate_input("((case-lambda ((a) a) ((a b) b)))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "case-lambda has no clause accepting 0 args"
+    );
+
+    let result = evaluate_input(
+        "(define (count-down n)
+           ((case-lambda ((n) (if (= n 0) 'done (count-down (- n 1))))) n))
+         (count-down 10000)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "done");
+}
+
+#[test]
+fn test_define_record_type() {
+    let env = "(define-record-type <point>
+                 (make-point x y)
+                 point?
+                 (x point-x set-point-x!)
+                 (y point-y))";
+
+    let result = evaluate_input(&format!("{} (make-point 1 2)", env)).unwrap();
+    assert_eq!(result.to_string(), "#<record point x: 1 y: 2>");
+
+    let result = evaluate_input(&format!(
+        "{} (define p (make-point 1 2))
+         (list (point? p) (point? 1) (point-x p) (point-y p))",
+        env
+    ))
+    .unwrap();
+    assert_eq!(result.to_string(), "(true false 1 2)");
+
+    let result = evaluate_input(&format!(
+        "{} (define p (make-point 1 2))
+         (define q p)
+         (set-point-x! p 5)
+         (point-x q)",
+        env
+    ))
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(5.0))));
+
+    let result = evaluate_input(&format!(
+        "{} (list (equal? (make-point 1 2) (make-point 1 2))
+                  (equal? (make-point 1 2) (make-point 2 1)))",
+        env
+    ))
+    .unwrap();
+    assert_eq!(result.to_string(), "(true false)");
+
+    let result = evaluate_input(&format!("{} (point-x '(1 2))", env)).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "point-x expects a record of type <point>, found (1 2)"
+    );
+
+    let result = evaluate_input(&format!("{} (make-point 1)", env)).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "make-point expects at least 2 args, found 1"
+    );
+
+    let result = evaluate_input(
+        "(define-record-type <a> (make-a x) a? (x a-x))
+         (define-record-type <b> (make-b x) b? (x b-x))
+         (list (a? (make-b 1)) (equal? (make-a 1) (make-b 1)) <a>)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(false false #<record-type a>)");
+
+    let result = evaluate_input(
+        "(define (make-counter)
+           (define-record-type counter (new-counter) counter? (n count set-count!))
+           (define c (new-counter))
+           (set-count! c 0)
+           (lambda () (set-count! c (+ (count c) 1)) (count c)))
+         (define next (make-counter))
+         (next)
+         (next)",
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2.0))));
+
+    let result =
+        evaluate_input("(define-record-type <p> (make-p z) p? (x p-x)) 1").unwrap_err();
+    assert_eq!(result.to_string(), "z is not a field of <p>");
+}
+
+#[test]
+fn test_values() {
+    let result = evaluate_input("(call-with-values (lambda () (values 1 2)) +)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(3.0))));
+
+    let result = evaluate_input("(call-with-values (lambda () 5) list)").unwrap();
+    assert_eq!(result.to_string(), "(5)");
+
+    let result = evaluate_input("(call-with-values values list)").unwrap();
+    assert_eq!(result, Exp::new_list());
+
+    let result = evaluate_input("(values 1 (+ 1 1) 'c)").unwrap();
+    assert_eq!(result.to_string(), "1\n2\nc");
+
+    let result = evaluate_input("(values)").unwrap();
+    assert_eq!(result.to_string(), "");
+
+    let result =
+        evaluate_input("(call-with-values (lambda () (call/cc (lambda (k) (k 1 2)))) list)")
+            .unwrap();
+    assert_eq!(result.to_string(), "(1 2)");
+
+    let result = evaluate_input("(call-with-values (lambda () (values 1 2)) (lambda (a) a))")
+        .unwrap_err();
+    assert_eq!(result.to_string(), "(a) takes a maximum of 1 args, found 2");
+
+    // Arity errors name the formals as they were written
+    let result = evaluate_input("(let-values (((a b) (values 1))) a)").unwrap_err();
+    assert_eq!(result.to_string(), "(a b) expects at least 2 args, found 1");
+    let result = evaluate_input("(define-values (x y . z) (values 1))").unwrap_err();
+    assert_eq!(result.to_string(), "(x y . z) expects at least 2 args, found 1");
+
+    // Only continuations taking any number of values can be given several
+    for input in [
+        "(list (values 1 2))",
+        "(define x (values 1 2))",
+        "(+ 1 (values 2 3))",
+        "(if (values 1 2) 'yes 'no)",
+        "(let ((x (values 1 2))) x)",
+        "(define (f) (values 1 2)) (list (f))",
+        "(define x 0) (set! x (values 1 2))",
+    ] {
+        let result = evaluate_input(input).unwrap_err();
+        assert_eq!(
+            result.to_string(),
+            "Expected a single value, found 2 values"
+        );
+    }
+    let result = evaluate_input("(list (values))").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "Expected a single value, found 0 values"
+    );
+
+    // Values that are discarded or returned can be any number
+    let result = evaluate_input("(begin (values 1 2) (values) (list (values 3)))").unwrap();
+    assert_eq!(result.to_string(), "(3)");
+    let result = evaluate_input("(define (f) (values 1 2)) (define (g) (f)) (g)").unwrap();
+    assert_eq!(result.to_string(), "1\n2");
+    let result =
+        evaluate_input("(guard (e (#t (error-object-message e))) (+ 1 (values 2 3)))").unwrap();
+    assert_eq!(
+        result.to_string(),
+        "\"Expected a single value, found 2 values\""
+    );
+}
+
+#[test]
+fn test_let_values() {
+    let result = evaluate_input(
+        "(let-values (((a b) (values 1 2))
+                      ((c . d) (values 3 4 5)))
+           (list a b c d))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 2 3 (4 5))");
+
+    let result = evaluate_input(
+        "(define a 10)
+         (let-values (((a) (values 1)) ((b) (values a)))
+           (list a b))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 10)");
+
+    let result = evaluate_input(
+        "(define a 10)
+         (let*-values (((a) (values 1)) ((b) (values a)) (all (values a b)))
+           (list a b all))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 1 (1 1))");
+
+    let result = evaluate_input("(let*-values (((a b) (values 1 2 3))) a)").unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "(a b) takes a maximum of 2 args, found 3"
+    );
+}
+
+#[test]
+fn test_define_values() {
+    let result = evaluate_input("(define-values (q r) (values 7 2)) (list q r)").unwrap();
+    assert_eq!(result.to_string(), "(7 2)");
+
+    let result = evaluate_input(
+        "(define (f)
+           (define-values (first . rest) (values 1 2 3))
+           (list first rest))
+         (f)",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 (2 3))");
+
+    let result = evaluate_input("(define-values all (values 1 2)) all").unwrap();
+    assert_eq!(result.to_string(), "(1 2)");
+
+    let result = evaluate_input("(receive (a . rest) (values 1 2 3) (list a rest))").unwrap();
+    assert_eq!(result.to_string(), "(1 (2 3))");
+}
+
+#[test]
+fn test_promises() {
+    let mut env = env_with_log();
+    let result = evaluate_input_with_env(
+        "(define p (delay (begin (log 'computed) (+ 1 2))))
+         (list (promise? p) (force p) (force p))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(true 3 3)");
+    assert_eq!(logged(), "computed");
+
+    let result =
+        evaluate_input("(list (force (make-promise 5)) (force 6) (promise? (make-promise 5)))")
+            .unwrap();
+    assert_eq!(result.to_string(), "(5 6 true)");
+
+    let result = evaluate_input("(define p (delay 1)) (equal? (make-promise p) p)").unwrap();
+    assert_eq!(result, Exp::Atom(Value::Boolean(true)));
+
+    let result = evaluate_input("(delay 1)").unwrap();
+    assert_eq!(result.to_string(), "#<promise>");
+
+    // R7RS: a promise forced again while computing its value keeps the
+    // first value it returns
+    let result = evaluate_input(
+        "(define count 0)
+         (define p (delay (begin (set! count (+ count 1))
+                                 (if (> count 5) count (force p)))))
+         (define x 5)
+         (list (force p) (begin (set! x 10) (force p)))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(6 6)");
+}
+
+#[test]
+fn test_delay_force_iterative() {
+    let result = evaluate_input(
+        "(define (loop n)
+           (delay-force (if (= n 0) (delay 'done) (loop (- n 1)))))
+         (force (loop 10000))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "done");
+
+    let result = evaluate_input(
+        "(define r (delay 'ready))
+         (define s (delay-force r))
+         (list (force s) (force r))",
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(ready ready)");
+}
+
+#[test]
+fn test_streams() {
+    let mut env = env_with_prelude();
+    let result = evaluate_input_with_env(
+        "(define-stream (squares n) (stream-cons (* n n) (squares (+ n 1))))
+         (stream->list (stream-take 5 (squares 1)))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 4 9 16 25)");
+
+    let result = evaluate_input_with_env(
+        "(stream->list (stream-map (lambda (x) (* x 10)) (stream 1 2 3)))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(10 20 30)");
+
+    let result = evaluate_input_with_env(
+        "(list (stream-ref (stream-iterate (lambda (x) (* x 2)) 1) 10)
+               (stream->list (stream-from 3) 3)
+               (stream-null? (stream-drop 3 (list->stream '(1 2 3))))
+               (stream-pair? stream-null))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1024 (3 4 5) true false)");
+
+    let result = evaluate_input_with_env(
+        "(stream-car (stream-filter (lambda (x) (> x 2000)) (stream-from 0)))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(2001.0))));
+
+    let result = evaluate_input_with_env("(stream-car stream-null)", &mut env).unwrap_err();
+    assert_eq!(
+        result.to_string(),
+        "stream-car expects a non-empty stream, found #<promise>"
+    );
+}
+
+#[test]
+fn test_generators() {
+    let mut env = env_with_prelude();
+    let result = evaluate_input_with_env(
+        "(define g (make-generator (lambda (yield) (yield 1) (yield 2))))
+         (list (g) (g) (g) (g))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 2 #<eof> #<eof>)");
+
+    // Yielding from inside a recursion resumes it where it left off
+    let result = evaluate_input_with_env(
+        "(define (counter n)
+           (make-generator
+             (lambda (yield)
+               (let count ((n n))
+                 (unless (= n 0)
+                   (count (- n 1))
+                   (yield n))))))
+         (generator->list (counter 5))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 2 3 4 5)");
+
+    let result = evaluate_input_with_env(
+        "(define naturals
+           (make-generator (lambda (yield) (let loop ((n 0)) (yield n) (loop (+ n 1))))))
+         (define letters (list->generator '(a b c)))
+         (list (generator->list naturals 3) (letters) (naturals) (generator->list letters))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "((0 1 2) a 3 (b c))");
+
+    let result = evaluate_input("(list (eof-object? (eof-object)) (eof-object? '()))").unwrap();
+    assert_eq!(result.to_string(), "(true false)");
+}
+
+#[test]
+fn test_coroutines() {
+    let mut env = env_with_prelude();
+    let result = evaluate_input_with_env(
+        "(define sum
+           (make-coroutine
+             (lambda (yield x)
+               (let loop ((total x))
+                 (loop (+ total (yield total)))))))
+         (list (sum 1) (sum 2) (sum 3) (sum 10))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(1 3 6 16)");
+
+    let result = evaluate_input_with_env(
+        "(define add (make-coroutine (lambda (yield x) (+ x (yield 'ready)))))
+         (list (add 1) (add 5) (add 0))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(ready 6 #<eof>)");
+
+    // Two coroutines passing control back and forth through their caller
+    let result = evaluate_input_with_env(
+        "(define ping (make-coroutine (lambda (yield n) (let loop ((n n)) (loop (yield (+ n 1)))))))
+         (define pong (make-coroutine (lambda (yield n) (let loop ((n n)) (loop (yield (* n 2)))))))
+         (let loop ((n 0) (i 0))
+           (if (= i 4) n (loop (pong (ping n)) (+ i 1))))",
+        &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Exp::Atom(Value::Number(Rational::from(30.0))));
+}
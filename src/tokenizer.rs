@@ -1,7 +1,7 @@
 use crate::{
     buffer::Buffer,
     error::SchemeError,
-    types::{rational::Rational, SpecialForm, Value},
+    types::{rational::Rational, symbol::Symbol, SpecialForm, Value},
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -10,24 +10,44 @@ pub(crate) enum Token {
     EndExp,
     Dot,
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
     Literal(Value),
 }
 
 pub(crate) fn tokenize(input: &str, buffer: &mut Buffer) -> Result<(), SchemeError> {
+    // String literals can contain spaces and brackets, so they're split out
+    // before the rest of the input is broken up on whitespace
+    let mut rest = input;
+    while let Some(start) = rest.find('"') {
+        tokenize_code(&rest[..start], buffer)?;
+        let (string, remaining) = read_string(&rest[start + 1..])?;
+        buffer.push(Token::Literal(Value::String(string)))?;
+        rest = remaining;
+    }
+    tokenize_code(rest, buffer)
+}
+
+fn tokenize_code(input: &str, buffer: &mut Buffer) -> Result<(), SchemeError> {
     for token in input
         .replace("(", " ( ")
         .replace(")", " ) ")
         .replace("[", " ( ")
         .replace("]", " ) ")
         .replace("'", " ' ")
-        .replace("\"", " \" ")
+        .replace("`", " ` ")
+        .replace(",", " , ")
+        .replace(" , @", " ,@ ")
         .split_ascii_whitespace()
         .map(|token| match token {
             "(" | "[" => Ok(Token::StartExp),
             ")" | "]" => Ok(Token::EndExp),
             "." => Ok(Token::Dot),
             "'" => Ok(Token::Quote),
-            "\"" => todo!(),
+            "`" => Ok(Token::Quasiquote),
+            "," => Ok(Token::Unquote),
+            ",@" => Ok(Token::UnquoteSplicing),
             _ => tokenize_symbol(token),
         })
     {
@@ -36,16 +56,68 @@ pub(crate) fn tokenize(input: &str, buffer: &mut Buffer) -> Result<(), SchemeErr
     Ok(())
 }
 
+/// Reads the contents of a string literal up to its closing quote, returning
+/// the string and the input after it.
+fn read_string(input: &str) -> Result<(String, &str), SchemeError> {
+    let mut string = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &input[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, c)) => string.push(c),
+                None => break,
+            },
+            _ => string.push(c),
+        }
+    }
+    Err(SchemeError::new("Unterminated string literal".to_string()))
+}
+
 fn tokenize_symbol(token: &str) -> Result<Token, SchemeError> {
     let special_forms = [
-        "define", "let", "lambda", "if", "and", "or", "eval", "apply",
+        "define",
+        "set!",
+        "let",
+        "let*",
+        "letrec",
+        "letrec*",
+        "lambda",
+        "lambda*",
+        "define*",
+        "case-lambda",
+        "if",
+        "begin",
+        "cond",
+        "case",
+        "when",
+        "unless",
+        "and",
+        "or",
+        "eval",
+        "apply",
+        "define-syntax",
+        "define-macro",
+        "let-syntax",
+        "letrec-syntax",
+        "syntax-rules",
+        "guard",
+        "define-record-type",
+        "let-values",
+        "let*-values",
+        "define-values",
+        "receive",
+        "delay",
+        "delay-force",
     ];
 
-    if let Some(c) = token.chars().next() {
-        if c.is_ascii_digit()
-            // Numbers can start with . and -, but make sure it's not the whole token
-            || (['.', '-'].contains(&c) && ![".", "-"].contains(&token))
-        {
+    if !token.is_empty() {
+        // Numbers can start with - and ., but need a digit after them (so - and ... are symbols)
+        let digits = token.strip_prefix('-').unwrap_or(token);
+        let digits = digits.strip_prefix('.').unwrap_or(digits);
+        if digits.starts_with(|c: char| c.is_ascii_digit()) {
             Ok(Token::Literal(Value::Number(Rational::from(
                 token
                     .parse::<f32>()
@@ -55,10 +127,12 @@ fn tokenize_symbol(token: &str) -> Result<Token, SchemeError> {
             Ok(Token::Literal(Value::Boolean(true)))
         } else if ["false", "#f"].contains(&token.to_ascii_lowercase().as_str()) {
             Ok(Token::Literal(Value::Boolean(false)))
+        } else if let Some(keyword) = token.strip_prefix("#:") {
+            Ok(Token::Literal(Value::Keyword(keyword.to_string())))
         } else if special_forms.contains(&token.to_ascii_lowercase().as_str()) {
             Ok(Token::Literal(Value::SpecialForm(SpecialForm::from(token))))
         } else {
-            Ok(Token::Literal(Value::Symbol(token.to_string())))
+            Ok(Token::Literal(Value::Symbol(Symbol::new(token))))
         }
     } else {
         Err(SchemeError::new("Tried to parse empty token".to_string()))
@@ -1,23 +1,140 @@
-use self::{function::Function, rational::Rational};
-use crate::error::SchemeError;
-use std::fmt::{self, Display};
+use self::{
+    condition::Condition,
+    continuation::Continuation,
+    function::Function,
+    macros::Macro,
+    promise::Promise,
+    rational::Rational,
+    record::{Record, RecordType},
+    symbol::Symbol,
+};
+use crate::{
+    error::SchemeError,
+    gc::{self, Tracer},
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
 
+pub(crate) mod condition;
+pub(crate) mod continuation;
 pub(crate) mod default_env;
 pub(crate) mod function;
+pub(crate) mod macros;
+pub(crate) mod promise;
 pub(crate) mod rational;
+pub(crate) mod record;
+pub(crate) mod symbol;
 
 /*
-    Expressions
+    Expressions - lists are built from shared pairs, so taking the car or cdr
+    or consing onto a list never copies it. Pairs are mutable, so a change made
+    through one reference is seen by every other, and lists can be circular
 */
 
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Exp {
-    List(Vec<Exp>),
-    ImpList(Vec<Exp>),
+    Pair(Rc<Pair>),
+    // The empty list
+    Nil,
     Atom(Value),
 }
 
 impl Exp {
+    pub(crate) fn cons(car: Exp, cdr: Exp) -> Self {
+        Self::Pair(Rc::new_cyclic(|pair| Pair {
+            car: RefCell::new(car),
+            cdr: RefCell::new(cdr),
+            slot: gc::track_pair(pair),
+        }))
+    }
+
+    /// A proper list of the items.
+    pub(crate) fn list(items: Vec<Exp>) -> Self {
+        Self::improper_list(items, Self::Nil)
+    }
+
+    /// A list of the items whose final cdr is tail, improper unless tail is a
+    /// list itself.
+    pub(crate) fn improper_list(items: Vec<Exp>, tail: Exp) -> Self {
+        items
+            .into_iter()
+            .rev()
+            .fold(tail, |cdr, car| Self::cons(car, cdr))
+    }
+
+    pub(crate) fn car(&self) -> Result<Exp, SchemeError> {
+        match self {
+            Exp::Pair(pair) => Ok(pair.car()),
+            _ => Err(SchemeError::new(format!("Expected a pair, found {}", self))),
+        }
+    }
+
+    pub(crate) fn cdr(&self) -> Result<Exp, SchemeError> {
+        match self {
+            Exp::Pair(pair) => Ok(pair.cdr()),
+            _ => Err(SchemeError::new(format!("Expected a pair, found {}", self))),
+        }
+    }
+
+    pub(crate) fn set_car(&self, value: Exp) -> Result<(), SchemeError> {
+        match self {
+            Exp::Pair(pair) => {
+                *pair.car.borrow_mut() = value;
+                Ok(())
+            }
+            _ => Err(SchemeError::new(format!("Expected a pair, found {}", self))),
+        }
+    }
+
+    pub(crate) fn set_cdr(&self, value: Exp) -> Result<(), SchemeError> {
+        match self {
+            Exp::Pair(pair) => {
+                *pair.cdr.borrow_mut() = value;
+                Ok(())
+            }
+            _ => Err(SchemeError::new(format!("Expected a pair, found {}", self))),
+        }
+    }
+
+    /// The car and cdr of a pair, or None for anything else.
+    pub(crate) fn split_first(&self) -> Option<(Exp, Exp)> {
+        match self {
+            Exp::Pair(pair) => Some((pair.car(), pair.cdr())),
+            _ => None,
+        }
+    }
+
+    /// Iterates over the elements of a list, stopping at the final cdr.
+    pub(crate) fn iter(&self) -> ListIter {
+        ListIter { rest: self.clone() }
+    }
+
+    /// Whether this is a proper list, ending in the empty list. A circular
+    /// list is caught by a second cursor moving at half the speed, which the
+    /// first can only catch up with by going round a cycle.
+    pub(crate) fn is_list(&self) -> bool {
+        let (mut slow, mut fast) = (self.clone(), self.clone());
+        loop {
+            for _ in 0..2 {
+                fast = match &fast {
+                    Exp::Pair(pair) => pair.cdr(),
+                    Exp::Nil => return true,
+                    Exp::Atom(_) => return false,
+                };
+            }
+            slow = slow.cdr().unwrap();
+            if let (Exp::Pair(a), Exp::Pair(b)) = (&slow, &fast) {
+                if Rc::ptr_eq(a, b) {
+                    return false;
+                }
+            }
+        }
+    }
+
     pub(crate) fn unwrap_atom(&self) -> Result<Value, SchemeError> {
         match self {
             Exp::Atom(atom) => Ok(atom.clone()),
@@ -28,59 +145,272 @@ impl Exp {
         }
     }
 
+    /// The elements of a proper list.
     pub(crate) fn unwrap_list(&self) -> Result<Vec<Exp>, SchemeError> {
-        match self {
-            Exp::List(list) => Ok(list.clone()),
-            _ => Err(SchemeError::new(format!("Expected a list, found {}", self))),
+        match self.is_list() {
+            true => Ok(self.iter().collect()),
+            false => Err(SchemeError::new(format!("Expected a list, found {}", self))),
         }
     }
 
-    pub(crate) fn unwrap_imp_list(&self) -> Result<Vec<Exp>, SchemeError> {
-        match self {
-            Exp::ImpList(imp_list) => Ok(imp_list.clone()),
-            _ => Err(SchemeError::new(format!("Expected a list, found {}", self))),
-        }
+    pub(crate) fn new_list() -> Self {
+        Self::Nil
     }
 
-    pub(crate) fn new_list() -> Self {
-        Self::List(Vec::new())
+    /// The result of returning these values: a single value is itself,
+    /// anything else is wrapped up as multiple values.
+    pub(crate) fn from_values(mut values: Vec<Exp>) -> Self {
+        match values.len() {
+            1 => values.pop().unwrap(),
+            _ => Self::Atom(Value::Values(values)),
+        }
     }
 
-    pub(crate) fn imp_from(list: &[Exp]) -> Self {
-        Self::ImpList(list.to_vec())
+    /// The values returned by an expression, the inverse of from_values.
+    pub(crate) fn into_values(self) -> Vec<Exp> {
+        match self {
+            Self::Atom(Value::Values(values)) => values,
+            value => vec![value],
+        }
     }
 }
 
 impl Display for Exp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let string = match self {
-            Exp::List(list) => {
-                String::from("(")
-                    + list
-                        .iter()
-                        .map(|item| item.to_string())
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                        .as_str()
-                    + ")"
+        match self {
+            Exp::Atom(atom) => write!(f, "{}", atom),
+            Exp::Nil => write!(f, "()"),
+            Exp::Pair(_) => Printer::new(self).write(self, f),
+        }
+    }
+}
+
+/*
+    Printer - writes lists, labelling the pairs that are part of a cycle the
+    way write does, e.g. #0=(1 2 . #0#), so circular lists print finitely.
+    Lists nested in the cars are kept on a stack of their own rather than
+    recursed into, so no depth of nesting overflows the native stack.
+*/
+
+struct Printer {
+    cycles: HashSet<*const Pair>,
+    labels: HashMap<*const Pair, usize>,
+}
+
+// What is left to write of the lists being printed
+enum Print {
+    Exp(Exp),
+    // The rest of a list after this pair's car
+    Rest(Rc<Pair>),
+    Close,
+}
+
+impl Printer {
+    fn new(exp: &Exp) -> Self {
+        Self {
+            cycles: find_cycles(exp),
+            labels: HashMap::new(),
+        }
+    }
+
+    fn write(&mut self, exp: &Exp, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pending = vec![Print::Exp(exp.clone())];
+        while let Some(print) = pending.pop() {
+            match print {
+                Print::Exp(Exp::Pair(pair)) => self.open(pair, &mut pending, f)?,
+                Print::Exp(exp) => write!(f, "{}", exp)?,
+                Print::Rest(pair) => match pair.cdr() {
+                    Exp::Nil => write!(f, ")")?,
+                    Exp::Pair(next) if !self.cycles.contains(&Rc::as_ptr(&next)) => {
+                        write!(f, " ")?;
+                        pending.push(Print::Rest(next.clone()));
+                        pending.push(Print::Exp(next.car()));
+                    }
+                    tail => {
+                        write!(f, " . ")?;
+                        pending.push(Print::Close);
+                        pending.push(Print::Exp(tail));
+                    }
+                },
+                Print::Close => write!(f, ")")?,
             }
-            Exp::Atom(atom) => atom.to_string(),
-            Exp::ImpList(list) => {
-                let mut list_str: Vec<String> = list
-                    .iter()
-                    .map(|item| item.to_string())
-                    .collect::<Vec<String>>();
-                list_str.insert(list_str.len() - 1, ".".to_string());
-                String::from("(") + list_str.join(" ").as_str() + ")"
+        }
+        Ok(())
+    }
+
+    /// Starts writing a list, or just its label if it has been written before.
+    fn open(
+        &mut self,
+        pair: Rc<Pair>,
+        pending: &mut Vec<Print>,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        if self.cycles.contains(&Rc::as_ptr(&pair)) {
+            if let Some(label) = self.labels.get(&Rc::as_ptr(&pair)) {
+                return write!(f, "#{}#", label);
+            }
+            let label = self.labels.len();
+            self.labels.insert(Rc::as_ptr(&pair), label);
+            write!(f, "#{}=", label)?;
+        }
+        write!(f, "(")?;
+        pending.push(Print::Rest(pair.clone()));
+        pending.push(Print::Exp(pair.car()));
+        Ok(())
+    }
+}
+
+/// Finds the pairs that can be reached again from inside themselves. Walks the
+/// cdrs of each list in a loop and keeps the lists nested in the cars on a
+/// stack, each with the pairs of it on the path being explored, so a pair
+/// found on the path again closes a cycle.
+fn find_cycles(exp: &Exp) -> HashSet<*const Pair> {
+    let (mut path, mut done, mut cycles) = (HashSet::new(), HashSet::new(), HashSet::new());
+    let mut lists = vec![(exp.clone(), Vec::new())];
+    while let Some((rest, walked)) = lists.last_mut() {
+        let car = match rest.clone() {
+            Exp::Pair(pair) => {
+                let ptr = Rc::as_ptr(&pair);
+                if path.contains(&ptr) {
+                    cycles.insert(ptr);
+                    None
+                // Any cycle through a pair explored before has already been found
+                } else if !done.insert(ptr) {
+                    None
+                } else {
+                    path.insert(ptr);
+                    walked.push(ptr);
+                    *rest = pair.cdr();
+                    Some(pair.car())
+                }
             }
+            _ => None,
         };
-        write!(f, "{}", string)
+        match car {
+            Some(car) => lists.push((car, Vec::new())),
+            None => {
+                let (_, walked) = lists.pop().unwrap();
+                for ptr in walked {
+                    path.remove(&ptr);
+                }
+            }
+        }
     }
+    cycles
 }
 
 impl From<&[Exp]> for Exp {
     fn from(list: &[Exp]) -> Self {
-        Self::List(list.to_vec())
+        Self::list(list.to_vec())
+    }
+}
+
+pub(crate) struct Pair {
+    car: RefCell<Exp>,
+    cdr: RefCell<Exp>,
+    // Where the heap tracks it
+    slot: usize,
+}
+
+impl Pair {
+    fn car(&self) -> Exp {
+        self.car.borrow().clone()
+    }
+
+    fn cdr(&self) -> Exp {
+        self.cdr.borrow().clone()
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.borrowed(&self.car, |tracer, car| tracer.exp(car));
+        tracer.borrowed(&self.cdr, |tracer, cdr| tracer.exp(cdr));
+    }
+
+    /// Takes the cdr out of a pair being freed, setting aside its car if it
+    /// is a pair too.
+    fn unlink(&mut self, nested: &mut Vec<Rc<Pair>>) -> Exp {
+        if let Exp::Pair(car) = std::mem::replace(self.car.get_mut(), Exp::Nil) {
+            nested.push(car);
+        }
+        std::mem::replace(self.cdr.get_mut(), Exp::Nil)
+    }
+
+    /// Takes the car and cdr out of a pair being collected, for the caller to
+    /// drop.
+    pub(crate) fn clear(&self) -> (Exp, Exp) {
+        (self.car.replace(Exp::Nil), self.cdr.replace(Exp::Nil))
+    }
+}
+
+impl Debug for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pair = Exp::cons(self.car(), self.cdr());
+        write!(f, "{}", pair)
+    }
+}
+
+// Pairs compared before are assumed equal, so circular lists are equal if
+// they never differ going round. Compares along the cdrs in a loop and keeps
+// the lists nested in the cars on a stack, so neither long nor deeply nested
+// lists can overflow the native stack. Closures in the lists compare by
+// identity, so comparing never follows them into the environments that may
+// hold the lists
+impl PartialEq for Pair {
+    fn eq(&self, other: &Self) -> bool {
+        let mut compared = HashSet::from([(self as *const Pair, other as *const Pair)]);
+        let mut pending = vec![(self.cdr(), other.cdr()), (self.car(), other.car())];
+        while let Some((a, b)) = pending.pop() {
+            match (&a, &b) {
+                // The same pairs, or ones already being compared
+                (Exp::Pair(x), Exp::Pair(y))
+                    if Rc::ptr_eq(x, y) || !compared.insert((Rc::as_ptr(x), Rc::as_ptr(y))) => {}
+                (Exp::Pair(x), Exp::Pair(y)) => {
+                    pending.push((x.cdr(), y.cdr()));
+                    pending.push((x.car(), y.car()));
+                }
+                _ if a != b => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+// Frees the pairs of a list in a loop rather than recursively along the cdrs,
+// each giving up its slot in the heap. Lists nested in the cars are set aside
+// to be freed the same way, so no depth of nesting overflows the stack.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        gc::untrack_pair(self.slot);
+        let mut nested = Vec::new();
+        let mut rest = self.unlink(&mut nested);
+        loop {
+            rest = match rest {
+                Exp::Pair(pair) => match Rc::try_unwrap(pair) {
+                    Ok(mut pair) => pair.unlink(&mut nested),
+                    Err(_) => Exp::Nil,
+                },
+                _ => match nested.pop() {
+                    Some(pair) => Exp::Pair(pair),
+                    None => break,
+                },
+            }
+        }
+    }
+}
+
+pub(crate) struct ListIter {
+    // The final cdr once the iterator is exhausted
+    pub(crate) rest: Exp,
+}
+
+impl Iterator for ListIter {
+    type Item = Exp;
+
+    fn next(&mut self) -> Option<Exp> {
+        let (car, cdr) = self.rest.split_first()?;
+        self.rest = cdr;
+        Some(car)
     }
 }
 
@@ -92,10 +422,49 @@ impl From<&[Exp]> for Exp {
 pub(crate) enum Value {
     Boolean(bool),
     Number(Rational),
-    Symbol(String),
+    String(String),
+    Symbol(Symbol),
+    // Self-evaluating #:name, used to pass keyword arguments
+    Keyword(String),
     SpecialForm(SpecialForm),
     Quote(Box<Exp>),
+    Quasiquote(Box<Exp>),
+    Unquote(Box<Exp>),
+    UnquoteSplicing(Box<Exp>),
     Function(Function),
+    Continuation(Continuation),
+    Condition(Condition),
+    Promise(Promise),
+    Record(Record),
+    RecordType(Rc<RecordType>),
+    // The end of a sequence, returned by exhausted generators
+    Eof,
+    // Returned by values with other than one argument
+    Values(Vec<Exp>),
+    Macro(Macro),
+}
+
+impl Value {
+    /// The expression inside a quote, quasiquote, unquote or unquote-splicing.
+    pub(crate) fn quoted(&self) -> Option<&Exp> {
+        match self {
+            Value::Quote(q)
+            | Value::Quasiquote(q)
+            | Value::Unquote(q)
+            | Value::UnquoteSplicing(q) => Some(q),
+            _ => None,
+        }
+    }
+
+    /// Wraps an expression in the same kind of quote as this value.
+    pub(crate) fn requote(&self, exp: Exp) -> Self {
+        match self {
+            Value::Quasiquote(_) => Value::Quasiquote(Box::new(exp)),
+            Value::Unquote(_) => Value::Unquote(Box::new(exp)),
+            Value::UnquoteSplicing(_) => Value::UnquoteSplicing(Box::new(exp)),
+            _ => Value::Quote(Box::new(exp)),
+        }
+    }
 }
 
 impl Display for Value {
@@ -106,10 +475,27 @@ impl Display for Value {
             match self {
                 Value::Boolean(b) => b.to_string(),
                 Value::Number(n) => n.to_string(),
+                Value::String(s) => format!("{:?}", s),
                 Value::Symbol(s) => s.to_string(),
+                Value::Keyword(k) => format!("#:{}", k),
                 Value::SpecialForm(sf) => sf.to_string(),
                 Value::Quote(q) => format!("'{}", q),
+                Value::Quasiquote(q) => format!("`{}", q),
+                Value::Unquote(q) => format!(",{}", q),
+                Value::UnquoteSplicing(q) => format!(",@{}", q),
                 Value::Function(f) => f.to_string(),
+                Value::Continuation(c) => c.to_string(),
+                Value::Condition(c) => c.to_string(),
+                Value::Promise(p) => p.to_string(),
+                Value::Record(r) => r.to_string(),
+                Value::RecordType(t) => t.to_string(),
+                Value::Eof => "#<eof>".to_string(),
+                Value::Values(values) => values
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Value::Macro(m) => m.to_string(),
             }
         )
     }
@@ -122,26 +508,76 @@ impl Display for Value {
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum SpecialForm {
     Define,
+    Set,
     Let,
+    LetStar,
+    Letrec,
+    LetrecStar,
     Lambda,
+    LambdaStar,
+    DefineStar,
+    CaseLambda,
     If,
+    Begin,
+    Cond,
+    Case,
+    When,
+    Unless,
     And,
     Or,
     Eval,
     Apply,
+    DefineSyntax,
+    DefineMacro,
+    LetSyntax,
+    LetrecSyntax,
+    SyntaxRules,
+    Guard,
+    DefineRecordType,
+    LetValues,
+    LetStarValues,
+    DefineValues,
+    Receive,
+    Delay,
+    DelayForce,
 }
 
 impl From<&str> for SpecialForm {
     fn from(s: &str) -> Self {
         match s.to_ascii_lowercase().as_str() {
             "define" => Self::Define,
+            "set!" => Self::Set,
             "let" => Self::Let,
+            "let*" => Self::LetStar,
+            "letrec" => Self::Letrec,
+            "letrec*" => Self::LetrecStar,
             "lambda" => Self::Lambda,
+            "lambda*" => Self::LambdaStar,
+            "define*" => Self::DefineStar,
+            "case-lambda" => Self::CaseLambda,
             "if" => Self::If,
+            "begin" => Self::Begin,
+            "cond" => Self::Cond,
+            "case" => Self::Case,
+            "when" => Self::When,
+            "unless" => Self::Unless,
             "and" => Self::And,
             "or" => Self::Or,
             "eval" => Self::Eval,
             "apply" => Self::Apply,
+            "define-syntax" => Self::DefineSyntax,
+            "define-macro" => Self::DefineMacro,
+            "let-syntax" => Self::LetSyntax,
+            "letrec-syntax" => Self::LetrecSyntax,
+            "syntax-rules" => Self::SyntaxRules,
+            "guard" => Self::Guard,
+            "define-record-type" => Self::DefineRecordType,
+            "let-values" => Self::LetValues,
+            "let*-values" => Self::LetStarValues,
+            "define-values" => Self::DefineValues,
+            "receive" => Self::Receive,
+            "delay" => Self::Delay,
+            "delay-force" => Self::DelayForce,
             _ => panic!("SpecialForm::from called on incorrect string"),
         }
     }
@@ -154,13 +590,38 @@ impl Display for SpecialForm {
             "{}",
             match self {
                 SpecialForm::Define => "define".to_string(),
+                SpecialForm::Set => "set!".to_string(),
                 SpecialForm::Let => "let".to_string(),
+                SpecialForm::LetStar => "let*".to_string(),
+                SpecialForm::Letrec => "letrec".to_string(),
+                SpecialForm::LetrecStar => "letrec*".to_string(),
                 SpecialForm::Lambda => "lambda".to_string(),
+                SpecialForm::LambdaStar => "lambda*".to_string(),
+                SpecialForm::DefineStar => "define*".to_string(),
+                SpecialForm::CaseLambda => "case-lambda".to_string(),
                 SpecialForm::If => "if".to_string(),
+                SpecialForm::Begin => "begin".to_string(),
+                SpecialForm::Cond => "cond".to_string(),
+                SpecialForm::Case => "case".to_string(),
+                SpecialForm::When => "when".to_string(),
+                SpecialForm::Unless => "unless".to_string(),
                 SpecialForm::And => "and".to_string(),
                 SpecialForm::Or => "or".to_string(),
                 SpecialForm::Eval => "eval".to_string(),
                 SpecialForm::Apply => "apply".to_string(),
+                SpecialForm::DefineSyntax => "define-syntax".to_string(),
+                SpecialForm::DefineMacro => "define-macro".to_string(),
+                SpecialForm::LetSyntax => "let-syntax".to_string(),
+                SpecialForm::LetrecSyntax => "letrec-syntax".to_string(),
+                SpecialForm::SyntaxRules => "syntax-rules".to_string(),
+                SpecialForm::Guard => "guard".to_string(),
+                SpecialForm::DefineRecordType => "define-record-type".to_string(),
+                SpecialForm::LetValues => "let-values".to_string(),
+                SpecialForm::LetStarValues => "let*-values".to_string(),
+                SpecialForm::DefineValues => "define-values".to_string(),
+                SpecialForm::Receive => "receive".to_string(),
+                SpecialForm::Delay => "delay".to_string(),
+                SpecialForm::DelayForce => "delay-force".to_string(),
             }
         )
     }
@@ -0,0 +1,44 @@
+use crate::{error::SchemeError, types::Exp};
+use std::fmt::{self, Display};
+
+/*
+    Condition - the object raised by error and by failing builtins, carrying
+    a message and a list of irritants
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Condition {
+    pub(crate) message: String,
+    pub(crate) irritants: Vec<Exp>,
+}
+
+impl Condition {
+    /// The message followed by the irritants, as reported when the condition
+    /// isn't handled.
+    pub(crate) fn report(&self) -> String {
+        [self.message.clone()]
+            .into_iter()
+            .chain(self.irritants.iter().map(|irritant| irritant.to_string()))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl From<SchemeError> for Condition {
+    fn from(error: SchemeError) -> Self {
+        Self {
+            message: error.message,
+            irritants: Vec::new(),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<condition {:?}", self.message)?;
+        for irritant in &self.irritants {
+            write!(f, " {}", irritant)?;
+        }
+        write!(f, ">")
+    }
+}
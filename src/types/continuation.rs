@@ -0,0 +1,70 @@
+use crate::{
+    evaluator::{trace_handlers, trace_winders, Frame, Handlers, Winders},
+    gc::Tracer,
+    types::Exp,
+};
+use std::{
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
+
+/*
+    Continuation - the rest of a computation, captured by call/cc as a copy of
+    the evaluator's frame stack, with the dynamic-wind winders and exception
+    handlers active when it was captured. The operands of any bytecode
+    waiting in those frames are copied along with them
+*/
+
+#[derive(Clone)]
+pub(crate) struct Continuation {
+    pub(crate) frames: Rc<[Frame]>,
+    pub(crate) values: Rc<[Exp]>,
+    pub(crate) winders: Winders,
+    pub(crate) handlers: Handlers,
+}
+
+impl Continuation {
+    pub(crate) fn new(
+        frames: Vec<Frame>,
+        values: Vec<Exp>,
+        winders: Winders,
+        handlers: Handlers,
+    ) -> Self {
+        Self {
+            frames: frames.into(),
+            values: values.into(),
+            winders,
+            handlers,
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.owned(&self.frames, |tracer, frames| {
+            frames.iter().for_each(|frame| frame.trace(tracer))
+        });
+        tracer.owned(&self.values, |tracer, values| {
+            values.iter().for_each(|value| tracer.exp(value))
+        });
+        trace_winders(&self.winders, tracer);
+        trace_handlers(&self.handlers, tracer);
+    }
+}
+
+impl Display for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<continuation>")
+    }
+}
+
+impl Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Continuation of {} frames", self.frames.len())
+    }
+}
+
+// Continuations are only equal to themselves
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.frames, &other.frames)
+    }
+}
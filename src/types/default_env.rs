@@ -1,8 +1,13 @@
 use crate::{
     environment::Env,
-    evaluator::validate_num_args,
+    evaluator::{validate_num_args, DEFAULT_MAX_DEPTH},
+    expander::expand_once,
+    gc,
     types::{
-        function::{Builtin, Function},
+        condition::Condition,
+        function::{Builtin, Control, Function},
+        promise::{Promise, PromiseState},
+        symbol::Symbol,
         Exp, Rational, SchemeError, Value,
     },
 };
@@ -72,6 +77,20 @@ pub(crate) fn builtins_map() -> HashMap<String, Exp> {
                 name: "=".to_string(),
             }))),
         ),
+        (
+            "equal?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &equal,
+                name: "equal?".to_string(),
+            }))),
+        ),
+        (
+            "eq?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &is_eq,
+                name: "eq?".to_string(),
+            }))),
+        ),
         (
             "<".to_string(),
             Exp::Atom(Value::Function(Function::Builtin(Builtin {
@@ -133,6 +152,226 @@ pub(crate) fn builtins_map() -> HashMap<String, Exp> {
                 name: "cdr".to_string(),
             }))),
         ),
+        (
+            "append".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &append,
+                name: "append".to_string(),
+            }))),
+        ),
+        (
+            "set-car!".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &set_car,
+                name: "set-car!".to_string(),
+            }))),
+        ),
+        (
+            "set-cdr!".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &set_cdr,
+                name: "set-cdr!".to_string(),
+            }))),
+        ),
+        (
+            "list-set!".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &list_set,
+                name: "list-set!".to_string(),
+            }))),
+        ),
+        (
+            "append!".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &append_mut,
+                name: "append!".to_string(),
+            }))),
+        ),
+        (
+            "reverse!".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &reverse_mut,
+                name: "reverse!".to_string(),
+            }))),
+        ),
+        /*
+
+            Continuations
+
+        */
+        (
+            "call/cc".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::CallCC))),
+        ),
+        (
+            "call-with-current-continuation".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::CallCC))),
+        ),
+        (
+            "dynamic-wind".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::DynamicWind))),
+        ),
+        /*
+
+            Promises
+
+        */
+        (
+            "force".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::Force))),
+        ),
+        (
+            "make-promise".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &make_promise,
+                name: "make-promise".to_string(),
+            }))),
+        ),
+        (
+            "promise?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &is_promise,
+                name: "promise?".to_string(),
+            }))),
+        ),
+        /*
+
+            Generators
+
+        */
+        (
+            "eof-object".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &eof_object,
+                name: "eof-object".to_string(),
+            }))),
+        ),
+        (
+            "eof-object?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &is_eof_object,
+                name: "eof-object?".to_string(),
+            }))),
+        ),
+        /*
+
+            Multiple values
+
+        */
+        (
+            "values".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &values,
+                name: "values".to_string(),
+            }))),
+        ),
+        (
+            "call-with-values".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::CallWithValues))),
+        ),
+        /*
+
+            Exceptions
+
+        */
+        (
+            "with-exception-handler".to_string(),
+            Exp::Atom(Value::Function(Function::Control(
+                Control::WithExceptionHandler,
+            ))),
+        ),
+        (
+            "raise".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::Raise))),
+        ),
+        (
+            "raise-continuable".to_string(),
+            Exp::Atom(Value::Function(Function::Control(
+                Control::RaiseContinuable,
+            ))),
+        ),
+        (
+            "error".to_string(),
+            Exp::Atom(Value::Function(Function::Control(Control::Error))),
+        ),
+        (
+            "error-object?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &error_object,
+                name: "error-object?".to_string(),
+            }))),
+        ),
+        (
+            "error-object-message".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &error_object_message,
+                name: "error-object-message".to_string(),
+            }))),
+        ),
+        (
+            "error-object-irritants".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &error_object_irritants,
+                name: "error-object-irritants".to_string(),
+            }))),
+        ),
+        /*
+
+            Macros
+
+        */
+        (
+            "macroexpand".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &macroexpand,
+                name: "macroexpand".to_string(),
+            }))),
+        ),
+        (
+            "macroexpand-1".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &macroexpand_1,
+                name: "macroexpand-1".to_string(),
+            }))),
+        ),
+        /*
+
+            Memory
+
+        */
+        (
+            "gc".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &gc,
+                name: "gc".to_string(),
+            }))),
+        ),
+        (
+            "heap-stats".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &heap_stats,
+                name: "heap-stats".to_string(),
+            }))),
+        ),
+        /*
+
+            Symbols
+
+        */
+        (
+            "string->symbol".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &string_to_symbol,
+                name: "string->symbol".to_string(),
+            }))),
+        ),
+        (
+            "symbol->string".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &symbol_to_string,
+                name: "symbol->string".to_string(),
+            }))),
+        ),
         /*
 
             Type checking
@@ -152,6 +391,13 @@ pub(crate) fn builtins_map() -> HashMap<String, Exp> {
                 name: "symbol?".to_string(),
             }))),
         ),
+        (
+            "string?".to_string(),
+            Exp::Atom(Value::Function(Function::Builtin(Builtin {
+                func: &string,
+                name: "string?".to_string(),
+            }))),
+        ),
         (
             "empty?".to_string(),
             Exp::Atom(Value::Function(Function::Builtin(Builtin {
@@ -197,8 +443,11 @@ pub(crate) fn display(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, Schem
     let args = args.unwrap_list()?;
     validate_num_args("display", &args, 1, 1)?;
 
-    print!("{}", args[0]);
-    Ok(Exp::List(Vec::new()))
+    match &args[0] {
+        Exp::Atom(Value::String(s)) => print!("{}", s),
+        exp => print!("{}", exp),
+    }
+    Ok(Exp::new_list())
 }
 
 pub(crate) fn newline(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
@@ -206,7 +455,7 @@ pub(crate) fn newline(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, Schem
     validate_num_args("display", &args, 0, 0)?;
 
     println!();
-    Ok(Exp::List(Vec::new()))
+    Ok(Exp::new_list())
 }
 
 /*
@@ -305,6 +554,31 @@ pub(crate) fn eq(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeErro
     Ok(Exp::Atom(Value::Boolean(args[0] == args[1])))
 }
 
+/// Structural equality: lists are equal if their elements are, and records if
+/// they have the same type and equal fields. Procedures have no structure to
+/// compare, so are only equal to themselves.
+pub(crate) fn equal(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("equal?", &args, 2, 2)?;
+    Ok(Exp::Atom(Value::Boolean(args[0] == args[1])))
+}
+
+/// Identity: pairs, records, procedures, continuations and promises are only
+/// eq? to themselves, while other values can't be told apart from copies of
+/// themselves so are compared by value.
+pub(crate) fn is_eq(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("eq?", &args, 2, 2)?;
+    let same = match (&args[0], &args[1]) {
+        (Exp::Pair(a), Exp::Pair(b)) => Rc::ptr_eq(a, b),
+        (Exp::Atom(Value::Record(a)), Exp::Atom(Value::Record(b))) => {
+            Rc::ptr_eq(&a.values, &b.values)
+        }
+        (a, b) => a == b,
+    };
+    Ok(Exp::Atom(Value::Boolean(same)))
+}
+
 pub(crate) fn gt(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
     validate_num_args(">", &args, 2, 2)?;
@@ -358,71 +632,348 @@ pub(crate) fn lte(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeErr
 */
 
 pub(crate) fn list(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
-    let args = args.unwrap_list()?;
-    Ok(Exp::List(args.to_vec()))
+    Ok(args.clone())
 }
 
 pub(crate) fn cons(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
     validate_num_args("cons", &args, 2, 2)?;
-    let first = args.get(0).unwrap();
-    let rest = args.get(1).unwrap();
-    match rest {
-        Exp::List(list) => {
-            let mut list = list.clone();
-            list.insert(0, first.clone());
-            Ok(Exp::from(&list[..]))
-        }
-        Exp::ImpList(list) => {
-            let mut list = list.clone();
-            list.insert(0, first.clone());
-            Ok(Exp::imp_from(&list[..]))
-        }
-        Exp::Atom(atom) => Ok(Exp::imp_from(&[first.clone(), Exp::Atom(atom.clone())])),
-    }
+    Ok(Exp::cons(args[0].clone(), args[1].clone()))
 }
 
 pub(crate) fn car(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
     validate_num_args("car", &args, 1, 1)?;
+    match &args[0] {
+        Exp::Pair(_) => args[0].car(),
+        Exp::Nil => Err(SchemeError::new("car called on empty list".to_string())),
+        Exp::Atom(atom) => Err(SchemeError::new(format!("car called on non-pair {}", atom))),
+    }
+}
+
+pub(crate) fn cdr(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("cdr", &args, 1, 1)?;
+    match &args[0] {
+        Exp::Pair(_) => args[0].cdr(),
+        Exp::Nil => Err(SchemeError::new("cdr called on empty list".to_string())),
+        Exp::Atom(atom) => Err(SchemeError::new(format!("cdr called on non-pair {}", atom))),
+    }
+}
 
-    match args.get(0).unwrap() {
-        Exp::List(list) => {
-            match list.get(0) {
-                Some(car) => Ok(car.clone()),
-                None => Err(SchemeError::new("car called on empty list".to_string())),
+/// Copies every list but the last, which the result shares.
+pub(crate) fn append(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    let (last, init) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Exp::new_list()),
+    };
+    let mut items = Vec::new();
+    for list in init {
+        match list.unwrap_list() {
+            Ok(list) => items.extend(list),
+            Err(_) => {
+                return Err(SchemeError::new(format!(
+                    "append expects proper lists, found {}",
+                    list
+                )))
             }
-            //
         }
-        Exp::ImpList(imp_list) => {
-            match imp_list.get(0) {
-                Some(car) => Ok(car.clone()),
-                None => Err(SchemeError::new("car called on empty list".to_string())),
+    }
+    Ok(Exp::improper_list(items, last.clone()))
+}
+
+pub(crate) fn set_car(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("set-car!", &args, 2, 2)?;
+    match &args[0] {
+        Exp::Pair(_) => args[0].set_car(args[1].clone())?,
+        pair => {
+            return Err(SchemeError::new(format!(
+                "set-car! called on non-pair {}",
+                pair
+            )))
+        }
+    }
+    Ok(Exp::new_list())
+}
+
+pub(crate) fn set_cdr(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("set-cdr!", &args, 2, 2)?;
+    match &args[0] {
+        Exp::Pair(_) => args[0].set_cdr(args[1].clone())?,
+        pair => {
+            return Err(SchemeError::new(format!(
+                "set-cdr! called on non-pair {}",
+                pair
+            )))
+        }
+    }
+    Ok(Exp::new_list())
+}
+
+pub(crate) fn list_set(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("list-set!", &args, 3, 3)?;
+    let index = match &args[1] {
+        Exp::Atom(Value::Number(k)) if k.eval() >= 0.0 && k.eval().fract() == 0.0 => {
+            k.eval() as usize
+        }
+        k => {
+            return Err(SchemeError::new(format!(
+                "list-set! expects an index, found {}",
+                k
+            )))
+        }
+    };
+    let mut pair = args[0].clone();
+    for _ in 0..index {
+        match pair {
+            Exp::Pair(_) => pair = pair.cdr()?,
+            _ => break,
+        }
+    }
+    match pair {
+        Exp::Pair(_) => {
+            pair.set_car(args[2].clone())?;
+            Ok(Exp::new_list())
+        }
+        _ => Err(SchemeError::new(format!(
+            "list-set! index {} is out of range for {}",
+            index, args[0]
+        ))),
+    }
+}
+
+/// Joins the lists by pointing the last cdr of each at the next, so the
+/// result shares every list rather than copying them.
+pub(crate) fn append_mut(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    let (last, init) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Exp::new_list()),
+    };
+    let mut result = last.clone();
+    for list in init.iter().rev() {
+        if !list.is_list() {
+            return Err(SchemeError::new(format!(
+                "append! expects proper lists, found {}",
+                list
+            )));
+        }
+        if let Exp::Pair(_) = list {
+            let mut pair = list.clone();
+            while let Exp::Pair(_) = pair.cdr()? {
+                pair = pair.cdr()?;
             }
-            //
+            pair.set_cdr(result)?;
+            result = list.clone();
         }
-        Exp::Atom(atom) => Err(SchemeError::new(format!("car called on non-pair {}", atom))),
     }
+    Ok(result)
 }
 
-pub(crate) fn cdr(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+/// Reverses a list in place by turning each cdr round to point at the pair
+/// before it, returning the old last pair as the new head.
+pub(crate) fn reverse_mut(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
-    validate_num_args("cdr", &args, 1, 1)?;
+    validate_num_args("reverse!", &args, 1, 1)?;
+    if !args[0].is_list() {
+        return Err(SchemeError::new(format!(
+            "reverse! expects a proper list, found {}",
+            args[0]
+        )));
+    }
+    let (mut reversed, mut rest) = (Exp::new_list(), args[0].clone());
+    while let Exp::Pair(_) = rest {
+        let next = rest.cdr()?;
+        rest.set_cdr(reversed)?;
+        reversed = rest;
+        rest = next;
+    }
+    Ok(reversed)
+}
 
-    match args.get(0).unwrap() {
-        Exp::List(list) => match list.len() {
-            0 => Err(SchemeError::new("cdr called on empty list".to_string())),
-            1 => Ok(Exp::new_list()),
-            _ => Ok(Exp::List(args.get(0).unwrap().unwrap_list()?[1..].to_vec())),
-        },
-        Exp::ImpList(imp_list) => match imp_list.len() {
-            0 | 1 => Err(SchemeError::new("cdr called on empty list".to_string())),
-            2 => Ok(imp_list.get(1).unwrap().clone()),
-            _ => Ok(Exp::ImpList(
-                args.get(0).unwrap().unwrap_imp_list()?[1..].to_vec(),
-            )),
-        },
-        Exp::Atom(atom) => Err(SchemeError::new(format!("car called on non-pair {}", atom))),
+/*
+    Promises
+*/
+
+/// A promise that is already done, unless the value is a promise already.
+pub(crate) fn make_promise(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("make-promise", &args, 1, 1)?;
+    match &args[0] {
+        promise @ Exp::Atom(Value::Promise(_)) => Ok(promise.clone()),
+        value => Ok(Exp::Atom(Value::Promise(Promise::new(PromiseState::Done(
+            value.clone(),
+        ))))),
+    }
+}
+
+pub(crate) fn is_promise(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("promise?", &args, 1, 1)?;
+    Ok(Exp::Atom(Value::Boolean(matches!(
+        args[0],
+        Exp::Atom(Value::Promise(_))
+    ))))
+}
+
+/*
+    Generators - the generators themselves are defined in the prelude, and
+    return the eof object once they are exhausted
+*/
+
+pub(crate) fn eof_object(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("eof-object", &args, 0, 0)?;
+    Ok(Exp::Atom(Value::Eof))
+}
+
+pub(crate) fn is_eof_object(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("eof-object?", &args, 1, 1)?;
+    Ok(Exp::Atom(Value::Boolean(matches!(
+        args[0],
+        Exp::Atom(Value::Eof)
+    ))))
+}
+
+/*
+    Multiple values
+*/
+
+/// A single value is returned as itself, so only call-with-values and the
+/// forms built on it need to know about multiple values.
+pub(crate) fn values(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    Ok(Exp::from_values(args.unwrap_list()?))
+}
+
+/*
+    Exceptions
+*/
+
+pub(crate) fn error_object(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("error-object?", &args, 1, 1)?;
+    Ok(Exp::Atom(Value::Boolean(matches!(
+        args[0],
+        Exp::Atom(Value::Condition(_))
+    ))))
+}
+
+fn unwrap_condition(name: &str, exp: &Exp) -> Result<Condition, SchemeError> {
+    match exp {
+        Exp::Atom(Value::Condition(condition)) => Ok(condition.clone()),
+        _ => Err(SchemeError::new(format!(
+            "{} expects an error object, found {}",
+            name, exp
+        ))),
+    }
+}
+
+pub(crate) fn error_object_message(
+    args: &Exp,
+    _: &mut Rc<RefCell<Env>>,
+) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("error-object-message", &args, 1, 1)?;
+    let condition = unwrap_condition("error-object-message", &args[0])?;
+    Ok(Exp::Atom(Value::String(condition.message)))
+}
+
+pub(crate) fn error_object_irritants(
+    args: &Exp,
+    _: &mut Rc<RefCell<Env>>,
+) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("error-object-irritants", &args, 1, 1)?;
+    let condition = unwrap_condition("error-object-irritants", &args[0])?;
+    Ok(Exp::list(condition.irritants))
+}
+
+/*
+    Macros
+*/
+
+pub(crate) fn macroexpand(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("macroexpand", &args, 1, 1)?;
+    let mut exp = args[0].clone();
+    while let Some(expanded) = expand_once(&exp, env, DEFAULT_MAX_DEPTH)? {
+        exp = expanded;
+    }
+    Ok(exp)
+}
+
+pub(crate) fn macroexpand_1(args: &Exp, env: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("macroexpand-1", &args, 1, 1)?;
+    Ok(expand_once(&args[0], env, DEFAULT_MAX_DEPTH)?.unwrap_or_else(|| args[0].clone()))
+}
+
+/*
+    Memory
+*/
+
+/// Collects garbage, returning the number of objects freed.
+pub(crate) fn gc(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    validate_num_args("gc", &args.unwrap_list()?, 0, 0)?;
+    let freed = gc::collect();
+    Ok(Exp::Atom(Value::Number(Rational::from(freed as f32))))
+}
+
+/// The live environments and pairs, and the collections so far along with
+/// the objects they freed, as an association list.
+pub(crate) fn heap_stats(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    validate_num_args("heap-stats", &args.unwrap_list()?, 0, 0)?;
+    let stats = gc::stats();
+    Ok(Exp::list(
+        [
+            ("environments", stats.environments),
+            ("pairs", stats.pairs),
+            ("collections", stats.collections),
+            ("freed", stats.freed),
+            ("symbols", stats.symbols),
+        ]
+        .into_iter()
+        .map(|(name, count)| {
+            Exp::cons(
+                Exp::Atom(Value::Symbol(Symbol::new(name))),
+                Exp::Atom(Value::Number(Rational::from(count as f32))),
+            )
+        })
+        .collect(),
+    ))
+}
+
+/*
+    Symbols - converting goes through the symbol table, so a symbol made from
+    a string is eq? to the same symbol read from source
+*/
+
+pub(crate) fn string_to_symbol(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("string->symbol", &args, 1, 1)?;
+    match &args[0] {
+        Exp::Atom(Value::String(name)) => Ok(Exp::Atom(Value::Symbol(Symbol::new(name)))),
+        exp => Err(SchemeError::new(format!(
+            "Expected a string, found {}",
+            exp
+        ))),
+    }
+}
+
+pub(crate) fn symbol_to_string(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("symbol->string", &args, 1, 1)?;
+    match &args[0] {
+        Exp::Atom(Value::Symbol(symbol)) => Ok(Exp::Atom(Value::String(symbol.to_string()))),
+        exp => Err(SchemeError::new(format!(
+            "Expected a symbol, found {}",
+            exp
+        ))),
     }
 }
 
@@ -450,20 +1001,30 @@ pub(crate) fn symbol(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, Scheme
     }
 }
 
-pub(crate) fn empty(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+pub(crate) fn string(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
-    validate_num_args("empty?", &args, 1, 1)?;
-    if args.get(0).unwrap().unwrap_list()?.len() == 0 {
+    validate_num_args("string?", &args, 1, 1)?;
+    if let Value::String(_) = args[0].unwrap_atom()? {
         Ok(Exp::Atom(Value::Boolean(true)))
     } else {
         Ok(Exp::Atom(Value::Boolean(false)))
     }
 }
 
+pub(crate) fn empty(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
+    let args = args.unwrap_list()?;
+    validate_num_args("empty?", &args, 1, 1)?;
+    match &args[0] {
+        Exp::Nil => Ok(Exp::Atom(Value::Boolean(true))),
+        Exp::Pair(_) => Ok(Exp::Atom(Value::Boolean(false))),
+        atom => Err(SchemeError::new(format!("Expected a list, found {}", atom))),
+    }
+}
+
 pub(crate) fn function(args: &Exp, _: &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError> {
     let args = args.unwrap_list()?;
     validate_num_args("function?", &args, 1, 1)?;
-    if let Value::Function(_) = args.get(0).unwrap().unwrap_atom()? {
+    if let Value::Function(_) | Value::Continuation(_) = args.get(0).unwrap().unwrap_atom()? {
         Ok(Exp::Atom(Value::Boolean(true)))
     } else {
         Ok(Exp::Atom(Value::Boolean(false)))
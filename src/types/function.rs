@@ -1,10 +1,14 @@
 use crate::{
-    environment::Env,
-    evaluator::{eval_all, validate_num_args},
-    types::{Exp, SchemeError},
+    analyzer::{Node, Template},
+    compiler::Code,
+    environment::{create_closure, Env},
+    evaluator::validate_num_args,
+    types::{
+        macros::original_name, record::RecordProcedure, symbol::Symbol, Exp, SchemeError, Value,
+    },
 };
 use std::fmt::Debug;
-use std::{cell::RefCell, fmt::Display, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
 
 /*
     Function - allows calling lambdas and hardcoded functions
@@ -14,19 +18,10 @@ use std::{cell::RefCell, fmt::Display, rc::Rc};
 pub(crate) enum Function {
     Builtin(Builtin),
     Lambda(Lambda),
-}
-
-impl Function {
-    pub(crate) fn call(
-        &mut self,
-        args: &Exp,
-        env: &mut Rc<RefCell<Env>>,
-    ) -> Result<Exp, SchemeError> {
-        match self {
-            Function::Builtin(b) => (b.func)(args, env),
-            Function::Lambda(l) => l.eval(args),
-        }
-    }
+    // Calls the first lambda whose parameters accept the arguments
+    CaseLambda(Vec<Lambda>),
+    Control(Control),
+    Record(RecordProcedure),
 }
 
 impl Display for Function {
@@ -37,6 +32,16 @@ impl Display for Function {
             match self {
                 Function::Builtin(f) => f.name.clone(),
                 Function::Lambda(l) => l.to_string(),
+                Function::CaseLambda(clauses) => format!(
+                    "(case-lambda {})",
+                    clauses
+                        .iter()
+                        .map(|l| l.to_string())
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ),
+                Function::Control(c) => c.to_string(),
+                Function::Record(r) => r.name.clone(),
             }
         )
     }
@@ -46,9 +51,11 @@ impl Display for Function {
     Builtin - Type definition
 */
 
+pub(crate) type BuiltinFn = dyn Fn(&Exp, &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError>;
+
 #[derive(Clone)]
 pub(crate) struct Builtin {
-    pub(crate) func: &'static dyn Fn(&Exp, &mut Rc<RefCell<Env>>) -> Result<Exp, SchemeError>,
+    pub(crate) func: &'static BuiltinFn,
     pub(crate) name: String,
 }
 
@@ -76,50 +83,157 @@ impl PartialEq for Builtin {
 }
 
 /*
-    Lambda
+    Control - primitives that act on the evaluator's continuation, so are
+    applied by the evaluator itself rather than called like builtins
 */
 
 #[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Control {
+    CallCC,
+    DynamicWind,
+    WithExceptionHandler,
+    Raise,
+    RaiseContinuable,
+    Error,
+    CallWithValues,
+    Force,
+}
+
+impl Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Control::CallCC => "call/cc",
+                Control::DynamicWind => "dynamic-wind",
+                Control::WithExceptionHandler => "with-exception-handler",
+                Control::Raise => "raise",
+                Control::RaiseContinuable => "raise-continuable",
+                Control::Error => "error",
+                Control::CallWithValues => "call-with-values",
+                Control::Force => "force",
+            }
+        )
+    }
+}
+
+/*
+    Lambda
+*/
+
+#[derive(Clone, Debug)]
 pub(crate) struct Lambda {
-    pub(crate) params: Vec<String>,
-    pub(crate) body: Vec<Exp>,
+    // The analyzed lambda form
+    pub(crate) template: Rc<Template>,
     pub(crate) env: Rc<RefCell<Env>>,
+    // The compiled body, for lambdas made by the virtual machine
+    pub(crate) code: Option<Rc<Code>>,
+}
+
+// Closures are only equal to themselves: the same lambda form closed over the
+// same environment. Comparing environments by value would reach the closure
+// again through the frame holding it.
+impl PartialEq for Lambda {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.template, &other.template) && Rc::ptr_eq(&self.env, &other.env)
+    }
 }
 
 impl Lambda {
-    pub(crate) fn eval(&mut self, args: &Exp) -> Result<Exp, SchemeError> {
-        let args = args.unwrap_list()?;
-        validate_num_args(
-            &self.params_to_string(),
-            &args,
-            self.params.len(),
-            self.params.len(),
-        )?;
-        for (name, val) in self.params.iter().zip(args) {
-            self.env.borrow_mut().set(name, &val);
-        }
+    /// Binds the arguments to the parameters in a new frame whose parent is
+    /// the captured environment, returning the frame to evaluate the body in
+    /// along with the defaults of any parameters not given an argument, which
+    /// are left to be evaluated in that frame in order.
+    pub(crate) fn bind_args(
+        &self,
+        args: Vec<Exp>,
+    ) -> Result<(Rc<RefCell<Env>>, Defaults), SchemeError> {
+        let params = &self.template.params;
+        // The analyzed defaults, of the optional then the keyword parameters
+        let mut default_values = self.template.defaults.iter();
+        let max = match (&params.rest, params.keys.is_empty()) {
+            (None, true) => params.required.len() + params.optional.len(),
+            _ => usize::MAX,
+        };
+        let name = params.original().to_string();
+        validate_num_args(&name, &args, params.required.len(), max)?;
+        // The slots of the frame, in the order the parameters are in it
+        let mut slots = Vec::with_capacity(self.template.frame.len());
+        let mut defaults = Vec::new();
+        let mut args = args.into_iter().peekable();
 
-        eval_all(&self.body, &mut self.env)
-    }
+        slots.extend(args.by_ref().take(params.required.len()).map(Some));
+        for default in default_values.by_ref().take(params.optional.len()) {
+            // A keyword ends the optional arguments if there are keyword params
+            let is_keyword = |arg: &Exp| matches!(arg, Exp::Atom(Value::Keyword(_)));
+            let arg = args.next_if(|arg| params.keys.is_empty() || !is_keyword(arg));
+            if arg.is_none() {
+                defaults.push((slots.len(), default.clone()));
+            }
+            slots.push(arg);
+        }
+        let rest: Vec<Exp> = args.collect();
+        if !params.keys.is_empty() {
+            let mut given = HashMap::new();
+            for pair in rest.chunks(2) {
+                match pair {
+                    [Exp::Atom(Value::Keyword(key)), value]
+                        if params.keys.iter().any(|(name, _)| name.to_string() == *key) =>
+                    {
+                        given.insert(key.to_string(), value.clone());
+                    }
+                    [Exp::Atom(Value::Keyword(key)), _] => {
+                        return Err(SchemeError::new(format!(
+                            "Unknown keyword argument #:{}",
+                            key
+                        )))
+                    }
+                    [Exp::Atom(Value::Keyword(key))] => {
+                        return Err(SchemeError::new(format!(
+                            "Keyword argument #:{} has no value",
+                            key
+                        )))
+                    }
+                    _ => {
+                        return Err(SchemeError::new(format!(
+                            "Expected a keyword argument, found {}",
+                            pair[0]
+                        )))
+                    }
+                }
+            }
+            for ((name, _), default) in params.keys.iter().zip(default_values) {
+                let value = given.remove(&name.to_string());
+                if value.is_none() {
+                    defaults.push((slots.len(), default.clone()));
+                }
+                slots.push(value);
+            }
+        }
+        if params.rest.is_some() {
+            slots.push(Some(Exp::list(rest)));
+        }
 
-    fn params_to_string(&self) -> String {
-        format!(
-            "({})",
-            self.params
-                .iter()
-                .map(|s| s.as_str())
-                .collect::<Vec<&str>>()
-                .join(" ")
-        )
+        let frame = create_closure(self.env.clone(), self.template.frame.clone(), slots);
+        Ok((frame, defaults))
     }
 }
 
+/// The slots and defaults of parameters not given an argument.
+pub(crate) type Defaults = Vec<(usize, Node)>;
+
 impl Display for Lambda {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let string = format!(
-            "(lambda ({}) {})",
-            self.params.join(" "),
-            self.body
+            "({} {} {})",
+            match self.template.params.is_extended() {
+                true => "lambda*",
+                false => "lambda",
+            },
+            self.template.params,
+            self.template
+                .source
                 .iter()
                 .map(|exp| exp.to_string())
                 .reduce(|p, c| p + " " + &c)
@@ -128,3 +242,71 @@ impl Display for Lambda {
         write!(f, "{}", string)
     }
 }
+
+/*
+    Params - the parameters of a lambda. Optional and keyword parameters, from
+    lambda* and define*, can have a default expression
+*/
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Params {
+    pub(crate) required: Vec<Symbol>,
+    pub(crate) optional: Vec<(Symbol, Option<Exp>)>,
+    pub(crate) keys: Vec<(Symbol, Option<Exp>)>,
+    // Bound to a list of any arguments after the positional ones
+    pub(crate) rest: Option<Symbol>,
+}
+
+impl Params {
+    pub(crate) fn is_extended(&self) -> bool {
+        !self.optional.is_empty() || !self.keys.is_empty()
+    }
+
+    /// The parameters under the names they were written with, undoing the
+    /// renaming done by macros and derived forms, to show in errors.
+    fn original(&self) -> Self {
+        let original = |name: &Symbol| original_name(*name);
+        let with_defaults = |params: &[(Symbol, Option<Exp>)]| {
+            params
+                .iter()
+                .map(|(name, default)| (original(name), default.clone()))
+                .collect()
+        };
+        Self {
+            required: self.required.iter().map(original).collect(),
+            optional: with_defaults(&self.optional),
+            keys: with_defaults(&self.keys),
+            rest: self.rest.as_ref().map(original),
+        }
+    }
+
+    /// Whether a call with this many arguments matches the parameters.
+    pub(crate) fn accepts(&self, num_args: usize) -> bool {
+        num_args >= self.required.len()
+            && (self.rest.is_some()
+                || !self.keys.is_empty()
+                || num_args <= self.required.len() + self.optional.len())
+    }
+}
+
+impl Display for Params {
+    /// The parameters as they were written, e.g. (a b), (a b . rest), rest or
+    /// (a #:optional (b 1) #:key c)
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut items: Vec<String> = self.required.iter().map(|name| name.to_string()).collect();
+        for (marker, params) in [("#:optional", &self.optional), ("#:key", &self.keys)] {
+            if !params.is_empty() {
+                items.push(marker.to_string());
+            }
+            items.extend(params.iter().map(|(name, default)| match default {
+                Some(default) => format!("({} {})", name, default),
+                None => name.to_string(),
+            }));
+        }
+        match (&self.rest, items.is_empty()) {
+            (Some(rest), true) => write!(f, "{}", rest),
+            (Some(rest), false) => write!(f, "({} . {})", items.join(" "), rest),
+            (None, _) => write!(f, "({})", items.join(" ")),
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use crate::{
+    environment::Env,
+    error::SchemeError,
+    evaluator::call,
+    types::{
+        function::{Function, Lambda},
+        symbol::Symbol,
+        Exp, Value,
+    },
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Display},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/*
+    Macros - transformers from one expression to another, applied by the
+    expander before evaluation
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Macro {
+    SyntaxRules(SyntaxRules),
+    // Non-hygienic, called with the unevaluated arguments of the macro use
+    Lambda(Lambda),
+}
+
+fn is_underscore(name: Symbol) -> bool {
+    original_name(name) == Symbol::new("_")
+}
+
+/// Whether a literal of a macro, where the macro was defined, and an
+/// identifier in a use of it, where it is used, refer to the same binding.
+pub(crate) type SameBinding<'a> = &'a dyn Fn(Symbol, Symbol) -> bool;
+
+impl Macro {
+    /// Rewrites a use of the macro. Hygienic macros rename the identifiers
+    /// they introduce with the given mark, while procedures called to rewrite
+    /// it run with the given maximum depth.
+    pub(crate) fn transform(
+        &self,
+        form: &Exp,
+        mark: usize,
+        same_binding: SameBinding,
+        env: &Rc<RefCell<Env>>,
+        max_depth: usize,
+    ) -> Result<Exp, SchemeError> {
+        match self {
+            Macro::SyntaxRules(rules) => rules.expand(form, mark, same_binding),
+            Macro::Lambda(lambda) => call(
+                &Exp::Atom(Value::Function(Function::Lambda(lambda.clone()))),
+                &form.cdr()?,
+                &mut env.clone(),
+                max_depth,
+            ),
+        }
+    }
+}
+
+impl Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Macro::SyntaxRules(rules) => write!(f, "{}", rules),
+            Macro::Lambda(lambda) => write!(f, "{}", lambda),
+        }
+    }
+}
+
+/*
+    Aliases - identifiers introduced by a macro expansion are renamed with the
+    expansion's mark, printed "<name>[<mark>]", which can't be read back in as
+    a single symbol since the tokenizer treats brackets as parentheses, so
+    they can never capture or be captured by user identifiers
+*/
+
+static NEXT_MARK: AtomicUsize = AtomicUsize::new(1);
+
+pub(crate) fn new_mark() -> usize {
+    NEXT_MARK.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) fn alias(name: Symbol, mark: usize) -> Symbol {
+    name.alias(mark)
+}
+
+/// Splits an alias into the name it renames and its mark.
+pub(crate) fn unalias(name: Symbol) -> Option<(Symbol, usize)> {
+    name.unalias()
+}
+
+/// Removes every layer of renaming from an identifier.
+pub(crate) fn original_name(name: Symbol) -> Symbol {
+    match unalias(name) {
+        Some((base, _)) => original_name(base),
+        None => name,
+    }
+}
+
+/// Restores the original names of all identifiers in quoted data. Copies the
+/// data with a stack of its own, so quoted data can nest to any depth.
+pub(crate) fn strip_aliases(exp: &Exp) -> Exp {
+    enum Step {
+        Strip(Exp),
+        // Joins the stripped items, and the final cdr if any, into a list
+        Join(usize, bool),
+        // Wraps the stripped expression in the same kind of quote as the value
+        Requote(Value),
+    }
+    let mut steps = vec![Step::Strip(exp.clone())];
+    let mut stripped = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Strip(exp @ Exp::Pair(_)) => {
+                let (items, tail) = split_list(&exp).unwrap();
+                steps.push(Step::Join(items.len(), tail.is_some()));
+                steps.extend(tail.map(Step::Strip));
+                steps.extend(items.into_iter().rev().map(Step::Strip));
+            }
+            Step::Strip(Exp::Atom(Value::Symbol(name))) => {
+                stripped.push(Exp::Atom(Value::Symbol(original_name(name))))
+            }
+            Step::Strip(Exp::Atom(value)) => match value.quoted().cloned() {
+                Some(quoted) => {
+                    steps.push(Step::Requote(value));
+                    steps.push(Step::Strip(quoted));
+                }
+                None => stripped.push(Exp::Atom(value)),
+            },
+            Step::Strip(Exp::Nil) => stripped.push(Exp::Nil),
+            Step::Join(len, has_tail) => {
+                let tail = if has_tail { stripped.pop() } else { None };
+                let items = stripped.split_off(stripped.len() - len);
+                stripped.push(join_list(items, tail));
+            }
+            Step::Requote(value) => {
+                let quoted = stripped.pop().unwrap();
+                stripped.push(Exp::Atom(value.requote(quoted)));
+            }
+        }
+    }
+    stripped.pop().unwrap()
+}
+
+/// Splits a proper or improper list into its elements and final cdr, which is
+/// None for a proper list.
+pub(crate) fn split_list(exp: &Exp) -> Option<(Vec<Exp>, Option<Exp>)> {
+    if let Exp::Atom(_) = exp {
+        return None;
+    }
+    let mut iter = exp.iter();
+    let items = iter.by_ref().collect();
+    match iter.rest {
+        Exp::Nil => Some((items, None)),
+        tail => Some((items, Some(tail))),
+    }
+}
+
+/// Builds a list from its elements and final cdr.
+pub(crate) fn join_list(items: Vec<Exp>, tail: Option<Exp>) -> Exp {
+    Exp::improper_list(items, tail.unwrap_or(Exp::Nil))
+}
+
+/*
+    syntax-rules
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SyntaxRules {
+    pub(crate) ellipsis: Option<Symbol>,
+    pub(crate) literals: Vec<Symbol>,
+    pub(crate) rules: Vec<(Exp, Exp)>,
+}
+
+#[derive(Clone, Debug)]
+enum Matched {
+    One(Exp),
+    Many(Vec<Matched>),
+}
+
+type Bindings = HashMap<Symbol, Matched>;
+
+impl SyntaxRules {
+    /// Builds a transformer from the arguments of a syntax-rules form.
+    pub(crate) fn from(args: &[Exp]) -> Result<Self, SchemeError> {
+        let (ellipsis, args) = match args.first() {
+            Some(Exp::Atom(Value::Symbol(ellipsis))) => (*ellipsis, &args[1..]),
+            _ => (Symbol::new("..."), args),
+        };
+        let literals = match args.first().map(Exp::unwrap_list) {
+            Some(Ok(literals)) => literals
+                .iter()
+                .map(|literal| match literal {
+                    Exp::Atom(Value::Symbol(name)) => Ok(*name),
+                    _ => Err(SchemeError::new(format!(
+                        "syntax-rules literals must be symbols, found {}",
+                        literal
+                    ))),
+                })
+                .collect::<Result<Vec<Symbol>, SchemeError>>()?,
+            _ => {
+                return Err(SchemeError::new(
+                    "syntax-rules expects a list of literals".to_string(),
+                ))
+            }
+        };
+        let rules = args[1..]
+            .iter()
+            .map(|rule| match rule.unwrap_list().as_deref() {
+                Ok([pattern, template]) => Ok((pattern.clone(), template.clone())),
+                _ => Err(SchemeError::new(format!(
+                    "syntax-rules expects (pattern template) rules, found {}",
+                    rule
+                ))),
+            })
+            .collect::<Result<Vec<(Exp, Exp)>, SchemeError>>()?;
+        let ellipsis = match literals.contains(&ellipsis) {
+            true => None,
+            false => Some(ellipsis),
+        };
+
+        Ok(Self {
+            ellipsis,
+            literals,
+            rules,
+        })
+    }
+
+    fn expand(
+        &self,
+        form: &Exp,
+        mark: usize,
+        same_binding: SameBinding,
+    ) -> Result<Exp, SchemeError> {
+        let (items, tail) = split_list(form)
+            .ok_or_else(|| SchemeError::new(format!("Invalid macro use {}", form)))?;
+        for (pattern, template) in &self.rules {
+            let (patterns, pattern_tail) = match split_list(pattern) {
+                Some((patterns, tail)) if !patterns.is_empty() => (patterns, tail),
+                _ => {
+                    return Err(SchemeError::new(format!(
+                        "syntax-rules patterns must be lists, found {}",
+                        pattern
+                    )))
+                }
+            };
+            // The keyword position is ignored
+            let mut bindings = HashMap::new();
+            if self.match_list(
+                &patterns[1..],
+                pattern_tail.as_ref(),
+                &items[1..],
+                tail.as_ref(),
+                &mut bindings,
+                same_binding,
+            )? {
+                return self.instantiate(template, &bindings, mark, false);
+            }
+        }
+        Err(SchemeError::new(format!("No syntax rule matches {}", form)))
+    }
+
+    fn is_ellipsis(&self, exp: &Exp) -> bool {
+        match (exp, &self.ellipsis) {
+            (Exp::Atom(Value::Symbol(name)), Some(ellipsis)) => {
+                original_name(*name) == original_name(*ellipsis)
+            }
+            _ => false,
+        }
+    }
+
+    fn is_literal(&self, name: Symbol) -> bool {
+        self.literals
+            .iter()
+            .any(|literal| original_name(*literal) == original_name(name))
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &Exp,
+        input: &Exp,
+        bindings: &mut Bindings,
+        same_binding: SameBinding,
+    ) -> Result<bool, SchemeError> {
+        match pattern {
+            Exp::Atom(Value::Symbol(name)) if is_underscore(*name) => Ok(true),
+            Exp::Atom(Value::Symbol(name)) if self.is_literal(*name) => Ok(match input {
+                Exp::Atom(Value::Symbol(input)) => {
+                    original_name(*input) == original_name(*name) && same_binding(*name, *input)
+                }
+                _ => false,
+            }),
+            Exp::Atom(Value::Symbol(name)) => {
+                bindings.insert(*name, Matched::One(input.clone()));
+                Ok(true)
+            }
+            Exp::Atom(_) | Exp::Nil => Ok(strip_aliases(pattern) == strip_aliases(input)),
+            Exp::Pair(_) => {
+                let (patterns, pattern_tail) = split_list(pattern).unwrap();
+                match split_list(input) {
+                    Some((items, tail)) => self.match_list(
+                        &patterns,
+                        pattern_tail.as_ref(),
+                        &items,
+                        tail.as_ref(),
+                        bindings,
+                        same_binding,
+                    ),
+                    None => Ok(false),
+                }
+            }
+        }
+    }
+
+    fn match_list(
+        &self,
+        patterns: &[Exp],
+        pattern_tail: Option<&Exp>,
+        items: &[Exp],
+        tail: Option<&Exp>,
+        bindings: &mut Bindings,
+        same_binding: SameBinding,
+    ) -> Result<bool, SchemeError> {
+        let ellipsis = patterns.iter().position(|p| self.is_ellipsis(p));
+        let (before, repeated, after) = match ellipsis {
+            Some(0) => {
+                return Err(SchemeError::new(
+                    "Ellipsis must follow a pattern".to_string(),
+                ))
+            }
+            Some(i) => (
+                &patterns[..i - 1],
+                Some(&patterns[i - 1]),
+                &patterns[i + 1..],
+            ),
+            None => (patterns, None, &patterns[patterns.len()..]),
+        };
+
+        let min = before.len() + after.len();
+        if items.len() < min
+            || (pattern_tail.is_none() && tail.is_some())
+            || (pattern_tail.is_none() && repeated.is_none() && items.len() != min)
+        {
+            return Ok(false);
+        }
+        let repeated_end = match repeated {
+            Some(_) => items.len() - after.len(),
+            None => before.len(),
+        };
+
+        for (pattern, item) in before.iter().zip(items) {
+            if !self.match_pattern(pattern, item, bindings, same_binding)? {
+                return Ok(false);
+            }
+        }
+        if let Some(repeated) = repeated {
+            let mut matches = Vec::new();
+            for item in &items[before.len()..repeated_end] {
+                let mut inner = HashMap::new();
+                if !self.match_pattern(repeated, item, &mut inner, same_binding)? {
+                    return Ok(false);
+                }
+                matches.push(inner);
+            }
+            for var in self.pattern_vars(repeated) {
+                let sequence = matches
+                    .iter_mut()
+                    .map(|m| m.remove(&var).unwrap())
+                    .collect();
+                bindings.insert(var, Matched::Many(sequence));
+            }
+        }
+        for (pattern, item) in after.iter().zip(&items[repeated_end..]) {
+            if !self.match_pattern(pattern, item, bindings, same_binding)? {
+                return Ok(false);
+            }
+        }
+        match pattern_tail {
+            Some(pattern_tail) => {
+                let rest = join_list(items[repeated_end + after.len()..].to_vec(), tail.cloned());
+                self.match_pattern(pattern_tail, &rest, bindings, same_binding)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn pattern_vars(&self, pattern: &Exp) -> Vec<Symbol> {
+        match pattern {
+            Exp::Atom(Value::Symbol(name))
+                if !is_underscore(*name)
+                    && !self.is_literal(*name)
+                    && !self.is_ellipsis(pattern) =>
+            {
+                vec![*name]
+            }
+            Exp::Pair(_) => {
+                let (items, tail) = split_list(pattern).unwrap();
+                items
+                    .iter()
+                    .chain(&tail)
+                    .flat_map(|item| self.pattern_vars(item))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn instantiate(
+        &self,
+        template: &Exp,
+        bindings: &Bindings,
+        mark: usize,
+        escaped: bool,
+    ) -> Result<Exp, SchemeError> {
+        match template {
+            Exp::Atom(Value::Symbol(name)) => match bindings.get(name) {
+                Some(Matched::One(exp)) => Ok(exp.clone()),
+                Some(Matched::Many(_)) => Err(SchemeError::new(format!(
+                    "Pattern variable {} used without an ellipsis",
+                    original_name(*name)
+                ))),
+                None => Ok(Exp::Atom(Value::Symbol(alias(*name, mark)))),
+            },
+            Exp::Atom(value) => match value.quoted() {
+                Some(quoted) => Ok(Exp::Atom(
+                    value.requote(self.instantiate(quoted, bindings, mark, escaped)?),
+                )),
+                None => Ok(template.clone()),
+            },
+            Exp::Nil => Ok(Exp::Nil),
+            Exp::Pair(_) => {
+                let (items, tail) = split_list(template).unwrap();
+                // (... template) escapes the ellipsis inside template
+                if !escaped && tail.is_none() && items.len() == 2 && self.is_ellipsis(&items[0]) {
+                    return self.instantiate(&items[1], bindings, mark, true);
+                }
+
+                let mut result = Vec::new();
+                let mut i = 0;
+                while i < items.len() {
+                    let depth = match escaped {
+                        true => 0,
+                        false => items[i + 1..]
+                            .iter()
+                            .take_while(|item| self.is_ellipsis(item))
+                            .count(),
+                    };
+                    result.extend(
+                        self.instantiate_repeated(&items[i], bindings, mark, escaped, depth)?,
+                    );
+                    i += 1 + depth;
+                }
+                let tail = match tail {
+                    Some(tail) => Some(self.instantiate(&tail, bindings, mark, escaped)?),
+                    None => None,
+                };
+                Ok(join_list(result, tail))
+            }
+        }
+    }
+
+    /// Instantiates a template followed by `depth` ellipses once for every
+    /// match of the pattern variables it contains.
+    fn instantiate_repeated(
+        &self,
+        template: &Exp,
+        bindings: &Bindings,
+        mark: usize,
+        escaped: bool,
+        depth: usize,
+    ) -> Result<Vec<Exp>, SchemeError> {
+        if depth == 0 {
+            return Ok(vec![self.instantiate(template, bindings, mark, escaped)?]);
+        }
+
+        let vars: Vec<(&Symbol, &Vec<Matched>)> = template_symbols(template)
+            .into_iter()
+            .filter_map(|name| match bindings.get_key_value(&name) {
+                Some((name, Matched::Many(matches))) => Some((name, matches)),
+                _ => None,
+            })
+            .collect();
+        let len = match vars.first() {
+            Some((_, matches)) => matches.len(),
+            None => {
+                return Err(SchemeError::new(format!(
+                    "No pattern variables before ellipsis in template {}",
+                    template
+                )))
+            }
+        };
+        if vars.iter().any(|(_, matches)| matches.len() != len) {
+            return Err(SchemeError::new(format!(
+                "Pattern variables in template {} matched different numbers of forms",
+                template
+            )));
+        }
+
+        let mut result = Vec::new();
+        for i in 0..len {
+            let mut inner = bindings.clone();
+            for (name, matches) in &vars {
+                inner.insert(**name, matches[i].clone());
+            }
+            result.extend(self.instantiate_repeated(template, &inner, mark, escaped, depth - 1)?);
+        }
+        Ok(result)
+    }
+}
+
+fn template_symbols(template: &Exp) -> Vec<Symbol> {
+    match template {
+        Exp::Atom(Value::Symbol(name)) => vec![*name],
+        Exp::Atom(value) => match value.quoted() {
+            Some(quoted) => template_symbols(quoted),
+            None => Vec::new(),
+        },
+        Exp::Pair(_) => {
+            let (items, tail) = split_list(template).unwrap();
+            items
+                .iter()
+                .chain(&tail)
+                .flat_map(template_symbols)
+                .collect()
+        }
+        Exp::Nil => Vec::new(),
+    }
+}
+
+impl Display for SyntaxRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ellipsis = match &self.ellipsis {
+            Some(ellipsis) if *ellipsis != Symbol::new("...") => format!("{} ", ellipsis),
+            _ => String::new(),
+        };
+        let rules = self
+            .rules
+            .iter()
+            .map(|(pattern, template)| format!(" ({} {})", pattern, template))
+            .collect::<String>();
+        write!(
+            f,
+            "(syntax-rules {}({}){})",
+            ellipsis,
+            self.literals
+                .iter()
+                .map(Symbol::to_string)
+                .collect::<Vec<String>>()
+                .join(" "),
+            rules
+        )
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{
+    gc::{self, Freed, Tracer},
+    types::{function::Lambda, Exp},
+};
+use std::{
+    cell::RefCell,
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
+
+/*
+    Promise - a value computed at most once, the first time it is forced.
+    delay-force chains are forced iteratively: when the promise returned by a
+    delay-force isn't done yet, the outer promise takes over its computation
+    and the inner promise forwards to the outer one, so each step replaces the
+    last instead of nesting inside it
+*/
+
+#[derive(Clone)]
+pub(crate) struct Promise {
+    state: Rc<RefCell<PromiseState>>,
+}
+
+#[derive(Clone)]
+pub(crate) enum PromiseState {
+    Done(Exp),
+    // A thunk returning the value of a delay, or of a delay-force if lazy
+    Delayed { thunk: Lambda, lazy: bool },
+    // Shares the state of a promise that took over this one's computation
+    Forward(Promise),
+}
+
+impl Promise {
+    pub(crate) fn new(state: PromiseState) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// The promise holding the state, following any forwarding.
+    pub(crate) fn resolve(&self) -> Promise {
+        let mut promise = self.clone();
+        loop {
+            let next = match &*promise.state.borrow() {
+                PromiseState::Forward(next) => next.clone(),
+                _ => return promise.clone(),
+            };
+            promise = next;
+        }
+    }
+
+    pub(crate) fn state(&self) -> PromiseState {
+        self.resolve().state.borrow().clone()
+    }
+
+    pub(crate) fn set_state(&self, state: PromiseState) {
+        *self.resolve().state.borrow_mut() = state;
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.owned(&self.state, |tracer, state| {
+            tracer.borrowed(state, |tracer, state| match state {
+                PromiseState::Done(value) => tracer.exp(value),
+                PromiseState::Delayed { thunk, .. } => tracer.lambda(thunk),
+                PromiseState::Forward(promise) => promise.trace(tracer),
+            })
+        })
+    }
+
+    /// Makes this promise take over the computation of another that isn't
+    /// done yet, leaving the other forwarding to this one.
+    pub(crate) fn adopt(&self, other: &Promise) {
+        let (this, other) = (self.resolve(), other.resolve());
+        if Rc::ptr_eq(&this.state, &other.state) {
+            return;
+        }
+        let state = other.state.replace(PromiseState::Forward(this.clone()));
+        *this.state.borrow_mut() = state;
+    }
+}
+
+// Sets aside the state of the last reference to a promise, so freeing a long
+// chain of promises doesn't recurse
+impl Drop for Promise {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.state) == 1 {
+            let state = self.state.replace(PromiseState::Done(Exp::Nil));
+            gc::free([Freed::Promise(state)]);
+        }
+    }
+}
+
+impl Display for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<promise>")
+    }
+}
+
+impl Debug for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state() {
+            PromiseState::Done(value) => write!(f, "Promise of {}", value),
+            _ => write!(f, "Promise"),
+        }
+    }
+}
+
+// Promises are only equal to themselves
+impl PartialEq for Promise {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.resolve().state, &other.resolve().state)
+    }
+}
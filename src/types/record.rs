@@ -0,0 +1,154 @@
+use crate::{
+    error::SchemeError,
+    evaluator::validate_num_args,
+    gc::{self, Freed},
+    types::{Exp, Value},
+};
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    rc::Rc,
+};
+
+/*
+    RecordType - a type created by define-record-type. Each definition makes a
+    distinct type, so types are only equal to themselves
+*/
+
+#[derive(Debug)]
+pub(crate) struct RecordType {
+    pub(crate) name: String,
+    pub(crate) fields: Vec<String>,
+}
+
+impl RecordType {
+    /// The type name without the angle brackets it is usually written with.
+    fn short_name(&self) -> &str {
+        self.name
+            .strip_prefix('<')
+            .and_then(|name| name.strip_suffix('>'))
+            .unwrap_or(&self.name)
+    }
+}
+
+impl PartialEq for RecordType {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<record-type {}>", self.short_name())
+    }
+}
+
+/*
+    Record - an instance of a record type. The fields are shared between
+    copies of the value so modifiers are seen by every reference to it
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record {
+    pub(crate) record_type: Rc<RecordType>,
+    pub(crate) values: Rc<RefCell<Vec<Exp>>>,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<record {}", self.record_type.short_name())?;
+        for (field, value) in self
+            .record_type
+            .fields
+            .iter()
+            .zip(self.values.borrow().iter())
+        {
+            write!(f, " {}: {}", field, value)?;
+        }
+        write!(f, ">")
+    }
+}
+
+// Sets aside the fields of the last reference to a record, so freeing a long
+// chain of records doesn't recurse
+impl Drop for Record {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.values) == 1 {
+            gc::free(self.values.take().into_iter().map(Freed::Exp));
+        }
+    }
+}
+
+/*
+    RecordProcedure - the constructor, predicate, accessors and modifiers
+    defined along with a record type
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RecordOperation {
+    // Takes the values of these fields, in order. The rest start out false
+    Constructor(Vec<usize>),
+    Predicate,
+    Accessor(usize),
+    Modifier(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RecordProcedure {
+    pub(crate) name: String,
+    pub(crate) record_type: Rc<RecordType>,
+    pub(crate) operation: RecordOperation,
+}
+
+impl RecordProcedure {
+    pub(crate) fn call(&self, args: Vec<Exp>) -> Result<Exp, SchemeError> {
+        match &self.operation {
+            RecordOperation::Constructor(fields) => {
+                validate_num_args(&self.name, &args, fields.len(), fields.len())?;
+                let mut values =
+                    vec![Exp::Atom(Value::Boolean(false)); self.record_type.fields.len()];
+                for (&field, value) in fields.iter().zip(args) {
+                    values[field] = value;
+                }
+                Ok(Exp::Atom(Value::Record(Record {
+                    record_type: self.record_type.clone(),
+                    values: Rc::new(RefCell::new(values)),
+                })))
+            }
+            RecordOperation::Predicate => {
+                validate_num_args(&self.name, &args, 1, 1)?;
+                Ok(Exp::Atom(Value::Boolean(matches!(
+                    &args[0],
+                    Exp::Atom(Value::Record(record))
+                        if Rc::ptr_eq(&record.record_type, &self.record_type)
+                ))))
+            }
+            RecordOperation::Accessor(field) => {
+                validate_num_args(&self.name, &args, 1, 1)?;
+                let record = self.unwrap_record(&args[0])?;
+                let value = record.values.borrow()[*field].clone();
+                Ok(value)
+            }
+            RecordOperation::Modifier(field) => {
+                validate_num_args(&self.name, &args, 2, 2)?;
+                let record = self.unwrap_record(&args[0])?;
+                record.values.borrow_mut()[*field] = args[1].clone();
+                Ok(Exp::new_list())
+            }
+        }
+    }
+
+    fn unwrap_record<'a>(&self, exp: &'a Exp) -> Result<&'a Record, SchemeError> {
+        match exp {
+            Exp::Atom(Value::Record(record))
+                if Rc::ptr_eq(&record.record_type, &self.record_type) =>
+            {
+                Ok(record)
+            }
+            _ => Err(SchemeError::new(format!(
+                "{} expects a record of type {}, found {}",
+                self.name, self.record_type.name, exp
+            ))),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+};
+
+/*
+    Symbol - an interned name. Each distinct name is stored once, for the life
+    of the program, in a table of names, and a symbol is its index in the
+    table, so symbols are copied and compared as integers.
+
+    An alias, the renaming of a name by a macro use or derived form, is the
+    name's index along with the mark of the expansion, so the fresh aliases
+    made by every expansion add nothing to the table. Only an alias of an
+    alias, made by a macro that defines a macro, interns the inner alias.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Symbol {
+    name: u32,
+    // 0 for a name that hasn't been renamed
+    mark: u32,
+}
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<&'static str>,
+    symbols: HashMap<&'static str, u32>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+impl Symbol {
+    /// The symbol for a name, adding the name to the table the first time.
+    /// A name written as an alias, "<name>[<mark>]", is read as one.
+    pub(crate) fn new(name: &str) -> Self {
+        let alias = name
+            .strip_suffix(']')
+            .and_then(|name| name.rsplit_once('['))
+            .and_then(|(base, mark)| Some((base, mark.parse::<u32>().ok()?)));
+        match alias {
+            Some((base, mark)) if mark > 0 => Symbol {
+                name: intern(base),
+                mark,
+            },
+            _ => Symbol {
+                name: intern(name),
+                mark: 0,
+            },
+        }
+    }
+
+    /// This symbol renamed by the expansion with the given mark.
+    pub(crate) fn alias(self, mark: usize) -> Self {
+        let name = match self.mark {
+            0 => self.name,
+            _ => intern(&self.to_string()),
+        };
+        Symbol {
+            name,
+            mark: mark as u32,
+        }
+    }
+
+    /// Splits an alias into the symbol it renames and its mark.
+    pub(crate) fn unalias(self) -> Option<(Self, usize)> {
+        match self.mark {
+            0 => None,
+            mark => Some((Symbol::new(name(self.name)), mark as usize)),
+        }
+    }
+}
+
+/// The number of names in the table.
+pub(crate) fn count() -> usize {
+    INTERNER.with(|interner| interner.borrow().names.len())
+}
+
+fn intern(name: &str) -> u32 {
+    INTERNER.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(index) = interner.symbols.get(name) {
+            return *index;
+        }
+        let name: &'static str = Box::leak(name.into());
+        let index = interner.names.len() as u32;
+        interner.names.push(name);
+        interner.symbols.insert(name, index);
+        index
+    })
+}
+
+fn name(index: u32) -> &'static str {
+    INTERNER.with(|interner| interner.borrow().names[index as usize])
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mark {
+            0 => write!(f, "{}", name(self.name)),
+            mark => write!(f, "{}[{}]", name(self.name), mark),
+        }
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
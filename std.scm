@@ -14,3 +14,130 @@
   (if (empty? ls)
     init
     (fn (car ls) (reduce fn init (cdr ls)))))
+
+(define-record-type <stream-pair>
+  (make-stream-pair kar kdr)
+  stream-pair-node?
+  (kar stream-kar)
+  (kdr stream-kdr))
+
+(define stream-null (delay '()))
+
+(define-syntax stream-lazy
+  (syntax-rules ()
+    ((_ expr) (delay-force expr))))
+
+(define-syntax stream-cons
+  (syntax-rules ()
+    ((_ obj strm) (make-promise (make-stream-pair (delay obj) (stream-lazy strm))))))
+
+(define-syntax define-stream
+  (syntax-rules ()
+    ((_ (name . params) body0 body ...)
+     (define (name . params) (stream-lazy (begin body0 body ...))))))
+
+(define (stream? obj) (promise? obj))
+
+(define (stream-null? strm) (equal? (force strm) '()))
+
+(define (stream-pair? strm)
+  (and (stream? strm) (stream-pair-node? (force strm))))
+
+(define (stream-car strm)
+  (if (stream-pair? strm)
+    (force (stream-kar (force strm)))
+    (error "stream-car expects a non-empty stream, found" strm)))
+
+(define (stream-cdr strm)
+  (if (stream-pair? strm)
+    (stream-kdr (force strm))
+    (error "stream-cdr expects a non-empty stream, found" strm)))
+
+(define (stream . objs) (list->stream objs))
+
+(define-stream (list->stream objs)
+  (if (empty? objs)
+    stream-null
+    (stream-cons (car objs) (list->stream (cdr objs)))))
+
+(define (stream->list strm . n)
+  (let loop ((strm strm) (n (if (empty? n) -1 (car n))))
+    (if (or (= n 0) (stream-null? strm))
+      '()
+      (cons (stream-car strm) (loop (stream-cdr strm) (- n 1))))))
+
+(define-stream (stream-from first)
+  (stream-cons first (stream-from (+ first 1))))
+
+(define-stream (stream-iterate fn base)
+  (stream-cons base (stream-iterate fn (fn base))))
+
+(define-stream (stream-take n strm)
+  (if (or (= n 0) (stream-null? strm))
+    stream-null
+    (stream-cons (stream-car strm) (stream-take (- n 1) (stream-cdr strm)))))
+
+(define-stream (stream-drop n strm)
+  (if (or (= n 0) (stream-null? strm))
+    strm
+    (stream-drop (- n 1) (stream-cdr strm))))
+
+(define (stream-ref strm n)
+  (stream-car (stream-drop n strm)))
+
+(define-stream (stream-map fn strm)
+  (if (stream-null? strm)
+    stream-null
+    (stream-cons (fn (stream-car strm)) (stream-map fn (stream-cdr strm)))))
+
+(define-stream (stream-filter pred strm)
+  (cond ((stream-null? strm) stream-null)
+        ((pred (stream-car strm))
+         (stream-cons (stream-car strm) (stream-filter pred (stream-cdr strm))))
+        (else (stream-filter pred (stream-cdr strm)))))
+
+(define (make-coroutine proc)
+  (define return #f)
+  (define resume #f)
+  (define (finished value) (return (eof-object)))
+  (define (yield value)
+    (call/cc
+      (lambda (k)
+        (set! resume k)
+        (return value))))
+  (lambda* (#:optional value)
+    (call/cc
+      (lambda (caller)
+        (set! return caller)
+        (if resume
+          (resume value)
+          (begin
+            (set! resume finished)
+            (let ((result (proc yield value)))
+              (set! resume finished)
+              (return result))))))))
+
+(define (make-generator proc)
+  (define coroutine
+    (make-coroutine
+      (lambda (yield value)
+        (proc yield)
+        (eof-object))))
+  (lambda () (coroutine)))
+
+(define (list->generator ls)
+  (make-generator
+    (lambda (yield)
+      (let loop ((ls ls))
+        (unless (empty? ls)
+          (yield (car ls))
+          (loop (cdr ls)))))))
+
+(define (generator->list gen . n)
+  (let loop ((n (if (empty? n) -1 (car n))))
+    (if (= n 0)
+      '()
+      (let ((value (gen)))
+        (if (eof-object? value)
+          '()
+          (cons value (loop (- n 1))))))))